@@ -0,0 +1,69 @@
+//! Timings for the hot ring operations, run with `cargo bench`. Uses plain `Instant` timing so
+//! it needs nothing beyond std; compare runs on the same machine only.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use consistenthash::{ConsistentHash, Node};
+
+const NODES: usize = 100;
+const REPLICAS: u32 = 160;
+const KEYS: usize = 200_000;
+// assignments that miss the cap walk far round the ring, so fewer of them
+const ASSIGNED_KEYS: usize = 5_000;
+
+fn ring() -> ConsistentHash {
+    let mut ch = ConsistentHash::with_load_factor(1.25);
+    for i in 0..NODES {
+        ch.add_node(&Node::new(format!("node_{}", i)), REPLICAS);
+    }
+    ch
+}
+
+fn report(name: &str, ops: usize, elapsed: Duration) {
+    println!("{:<12} {:>10.1} ns/op", name, elapsed.as_nanos() as f64 / ops as f64);
+}
+
+fn main() {
+    let keys: Vec<String> = (0..KEYS).map(|i| format!("key_{}", i)).collect();
+
+    let start = Instant::now();
+    for _ in 0..10 {
+        black_box(ring());
+    }
+    report("build", 10 * NODES, start.elapsed());
+
+    let ch = ring();
+    let start = Instant::now();
+    for key in keys.iter() {
+        black_box(ch.get_node(key.clone()));
+    }
+    report("get_node", KEYS, start.elapsed());
+
+    let compact = ch.compact();
+    let start = Instant::now();
+    for key in keys.iter() {
+        black_box(compact.get_node(key.clone()));
+    }
+    report("compact", KEYS, start.elapsed());
+    println!("{:<12} {:>10} bytes ring, {} bytes compact", "memory", ch.memory_footprint(), compact.memory_footprint());
+
+    let mut ch = ring();
+    let start = Instant::now();
+    for key in keys.iter().take(ASSIGNED_KEYS) {
+        let _ = black_box(ch.assign_key(key.clone()));
+    }
+    report("assign_key", ASSIGNED_KEYS, start.elapsed());
+
+    let start = Instant::now();
+    for i in 0..NODES / 2 {
+        black_box(ch.remove_node(format!("node_{}", i)));
+    }
+    report("remove_node", NODES / 2, start.elapsed());
+
+    let mut ch = ring();
+    ch.remove_node(String::from("node_0"));
+    let start = Instant::now();
+    let adjustments = black_box(ch.rebalance_replicas());
+    println!("{:<12} {:>10.1} ms, {} nodes adjusted", "rebalance", start.elapsed().as_secs_f64() * 1000.0, adjustments.len());
+}
@@ -0,0 +1,33 @@
+#![no_main]
+
+use consistenthash::{ConsistentHash, Node};
+use libfuzzer_sys::fuzz_target;
+
+// every 3 input bytes are one operation: an opcode, an operand picking the node or key, and a
+// replica count. Names and keys come from small pools so operations keep hitting the same ones.
+fuzz_target!(|data: &[u8]| {
+    let mut ch = ConsistentHash::with_load_factor(1.25);
+    ch.enable_key_tracking();
+    for op in data.chunks_exact(3) {
+        let node = format!("node_{}", op[1] % 8);
+        let key = format!("key_{}", op[1] % 64);
+        match op[0] % 5 {
+            0 => ch.add_node(&Node::new(node), u32::from(op[2] % 16)),
+            1 => {
+                ch.remove_node(node);
+            }
+            2 => {
+                let _ = ch.assign_key(key);
+            }
+            3 => {
+                ch.unassign_key(key);
+            }
+            _ => {
+                ch.get_node(key);
+            }
+        }
+        if let Err(errors) = ch.audit() {
+            panic!("ring invariants broken: {:?}", errors);
+        }
+    }
+});
@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use crate::{Node, Router};
+
+/// AnchorHash (Mendelson et al., https://arxiv.org/abs/1812.09674): consistent hashing over a
+/// fixed number of buckets with O(1) memory per bucket and no ring to walk.
+///
+/// The hash is built for `capacity` buckets up front and nodes take up buckets as they join.
+/// Removing a node only moves the keys it held, adding one only moves keys onto it, and keys are
+/// spread evenly over the working nodes without virtual nodes. Lookups take a few hash
+/// evaluations on average even after many removals, where a ring walking past over-capacity
+/// nodes can visit most of the ring.
+///
+/// There is no load accounting: this is plain consistent hashing, not bounded-load placement.
+#[derive(Clone, Debug)]
+pub struct AnchorHash {
+    // A[b] is 0 while bucket b is working, otherwise the working set size right after it was
+    // removed
+    removed_at: Vec<usize>,
+    // K[b]: the bucket that replaced b when it was removed
+    successor: Vec<usize>,
+    // W and L: the working buckets as an array, and each bucket's index in it
+    working: Vec<usize>,
+    location: Vec<usize>,
+    // R: removed buckets, the most recently removed on top
+    removed: Vec<usize>,
+    working_count: usize,
+
+    buckets: Vec<Option<Node>>,
+    bucket_by_name: HashMap<String, usize>,
+}
+
+impl AnchorHash {
+    /// Builds an empty hash with room for `capacity` nodes.
+    pub fn new(capacity: usize) -> AnchorHash {
+        AnchorHash {
+            removed_at: (0..capacity).collect(),
+            successor: (0..capacity).collect(),
+            working: (0..capacity).collect(),
+            location: (0..capacity).collect(),
+            removed: (0..capacity).rev().collect(),
+            working_count: 0,
+
+            buckets: vec![None; capacity],
+            bucket_by_name: HashMap::new(),
+        }
+    }
+
+    /// Gives a node the most recently freed bucket. Returns false if the node is already present
+    /// or every bucket is taken.
+    pub fn add_node(&mut self, node: &Node) -> bool {
+        if self.bucket_by_name.contains_key(node.get_name()) {
+            return false;
+        }
+        let bucket = match self.removed.pop() {
+            None => return false,
+            Some(bucket) => bucket,
+        };
+        self.removed_at[bucket] = 0;
+        self.location[self.working[self.working_count]] = self.working_count;
+        self.working[self.location[bucket]] = bucket;
+        self.successor[bucket] = bucket;
+        self.working_count += 1;
+
+        self.buckets[bucket] = Some(node.clone());
+        self.bucket_by_name.insert(node.get_name().clone(), bucket);
+        true
+    }
+
+    /// Frees a node's bucket, moving only the keys it held. Returns None if the node is unknown.
+    pub fn remove_node(&mut self, name: &str) -> Option<Node> {
+        let bucket = self.bucket_by_name.remove(name)?;
+        self.removed.push(bucket);
+        self.working_count -= 1;
+        self.removed_at[bucket] = self.working_count;
+        let last = self.working[self.working_count];
+        self.working[self.location[bucket]] = last;
+        self.location[last] = self.location[bucket];
+        self.successor[bucket] = last;
+
+        self.buckets[bucket].take()
+    }
+
+    pub fn get_node(&self, key: String) -> Option<Node> {
+        if self.working_count == 0 {
+            return None;
+        }
+        let key = key.as_bytes();
+        let capacity = self.buckets.len();
+        let mut bucket = (seeded_hash(key, capacity as u64) % capacity as u64) as usize;
+        while self.removed_at[bucket] > 0 {
+            // rehash among the buckets that were working when this one was removed, following
+            // replacements for any removed since
+            let mut next = (seeded_hash(key, bucket as u64) % self.removed_at[bucket] as u64) as usize;
+            while self.removed_at[next] >= self.removed_at[bucket] {
+                next = self.successor[next];
+            }
+            bucket = next;
+        }
+        self.buckets[bucket].clone()
+    }
+
+    /// Number of nodes currently holding a bucket.
+    pub fn size(&self) -> usize {
+        self.working_count
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+impl Router for AnchorHash {
+    fn route(&self, key: String) -> Option<Node> {
+        self.get_node(key)
+    }
+}
+
+// independent 64-bit hash of the key per seed, from the first 8 bytes of md5(seed || key)
+fn seeded_hash(key: &[u8], seed: u64) -> u64 {
+    let mut input = seed.to_be_bytes().to_vec();
+    input.extend_from_slice(key);
+    let digest = md5::compute(input);
+    u64::from_be_bytes([digest[0], digest[1], digest[2], digest[3], digest[4], digest[5], digest[6], digest[7]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(capacity: usize, nodes: usize) -> AnchorHash {
+        let mut anchor = AnchorHash::new(capacity);
+        for i in 0..nodes {
+            assert!(anchor.add_node(&Node::new(format!("test_node_{}", i))));
+        }
+        anchor
+    }
+
+    fn owners(anchor: &AnchorHash, keys: usize) -> Vec<Node> {
+        (0..keys).map(|i| anchor.get_node(format!("test_key{}", i)).unwrap()).collect()
+    }
+
+    #[test]
+    fn add_and_remove_nodes() {
+        let mut anchor = setup(4, 4);
+        assert!(!anchor.add_node(&Node::new(String::from("test_node_9"))), "no buckets left");
+        assert!(!anchor.add_node(&Node::new(String::from("test_node_0"))), "already present");
+        assert_eq!(anchor.size(), 4);
+
+        assert_eq!(anchor.remove_node("test_node_2"), Some(Node::new(String::from("test_node_2"))));
+        assert_eq!(anchor.remove_node("test_node_2"), None);
+        assert_eq!(anchor.size(), 3);
+        assert!(anchor.add_node(&Node::new(String::from("test_node_9"))));
+
+        assert_eq!(AnchorHash::new(4).get_node(String::from("test_key1")), None);
+    }
+
+    #[test]
+    fn removal_only_moves_the_removed_nodes_keys() {
+        let mut anchor = setup(16, 10);
+        let before = owners(&anchor, 2000);
+
+        anchor.remove_node("test_node_3");
+        anchor.remove_node("test_node_7");
+        let after = owners(&anchor, 2000);
+        for (old, new) in before.iter().zip(after.iter()) {
+            if old.get_name() != "test_node_3" && old.get_name() != "test_node_7" {
+                assert_eq!(old, new);
+            } else {
+                assert_ne!(old, new);
+            }
+        }
+
+        // adding a node back in only takes keys for itself
+        anchor.add_node(&Node::new(String::from("test_node_7")));
+        for (old, new) in after.iter().zip(owners(&anchor, 2000).iter()) {
+            assert!(old == new || new.get_name() == "test_node_7");
+        }
+    }
+
+    #[test]
+    fn keys_spread_evenly() {
+        let mut anchor = setup(32, 20);
+        for i in [1, 5, 6, 12, 19] {
+            anchor.remove_node(&format!("test_node_{}", i));
+        }
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for node in owners(&anchor, 15000) {
+            *counts.entry(node.get_name().clone()).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 15);
+        for (name, count) in counts {
+            assert!(count > 800 && count < 1200, "{} got {} keys", name, count);
+        }
+    }
+}
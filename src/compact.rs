@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+
+use crate::{ring_position, routed_key, ConsistentHash, HashAlgorithm, HashTagExtractor, Node, Router, RingWidth};
+
+/// A read-only copy of a ring's positions packed for lookups on very large rings, from
+/// `ConsistentHash::compact`.
+///
+/// Positions live in one sorted `Vec<u128>` with a parallel array of indices into the distinct
+/// nodes, so each position costs 20 bytes instead of a BTreeMap entry holding its own copy of the
+/// node, and a lookup is a binary search over contiguous memory. On the ring the benchmarks use
+/// (100 nodes of 160 replicas) that came to under a third of the ring's `memory_footprint`, with
+/// lookups over twice as fast; `cargo bench` prints both.
+///
+/// Lookups take the first position clockwise of the key, like `get_node_with_policy` with
+/// `LoadPolicy::Ignore`, but without load or node states: there is no bounded-load cap, and
+/// degraded or unhealthy nodes are routed to like any other. Changes to the ring after
+/// compacting are not seen; compact it again to pick them up.
+#[derive(Clone)]
+pub struct CompactRing {
+    hasher: HashAlgorithm,
+    ring_width: RingWidth,
+    hash_tag_extractor: Option<Arc<HashTagExtractor>>,
+    positions: Vec<u128>,
+    // owners[i] indexes into `nodes` for positions[i]
+    owners: Vec<u32>,
+    nodes: Vec<Node>,
+}
+
+impl ConsistentHash {
+    /// Packs the ring's current positions into a `CompactRing` for cheaper lookups.
+    pub fn compact(&self) -> CompactRing {
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut indices: HashMap<&str, u32> = HashMap::new();
+        let mut positions = Vec::with_capacity(self.nodes.len());
+        let mut owners = Vec::with_capacity(self.nodes.len());
+        for (hash, node) in self.nodes.iter() {
+            let index = *indices.entry(node.get_name()).or_insert_with(|| {
+                nodes.push(node.clone());
+                nodes.len() as u32 - 1
+            });
+            positions.push(*hash);
+            owners.push(index);
+        }
+        CompactRing {
+            hasher: self.hasher,
+            ring_width: self.ring_width,
+            hash_tag_extractor: self.hash_tag_extractor.clone(),
+            positions,
+            owners,
+            nodes,
+        }
+    }
+}
+
+impl CompactRing {
+    pub fn get_node(&self, key: String) -> Option<Node> {
+        if self.positions.is_empty() {
+            return None;
+        }
+        let routed = routed_key(self.hash_tag_extractor.as_deref(), &key);
+        let hash = ring_position(self.hasher, self.ring_width, routed.as_bytes());
+
+        let mut i = self.positions.partition_point(|position| *position < hash);
+        if i == self.positions.len() {
+            i = 0;
+        }
+        Some(self.nodes[self.owners[i] as usize].clone())
+    }
+
+    /// Number of ring positions.
+    pub fn size(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Bytes held by the positions, their owner indices and one copy of each node, counted
+    /// like `ConsistentHash::memory_footprint`.
+    pub fn memory_footprint(&self) -> usize {
+        let string_bytes = |value: &String| mem::size_of::<String>() + value.capacity();
+        let nodes: usize = self.nodes.iter()
+            .map(|node| {
+                mem::size_of::<Node>() + node.get_name().capacity()
+                    + node.tags().iter().map(|(key, value)| string_bytes(key) + string_bytes(value)).sum::<usize>()
+            })
+            .sum();
+        self.positions.len() * (mem::size_of::<u128>() + mem::size_of::<u32>()) + nodes
+    }
+}
+
+impl Router for CompactRing {
+    fn route(&self, key: String) -> Option<Node> {
+        self.get_node(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash_tag, LoadPolicy};
+
+    #[test]
+    fn routes_like_the_ring() {
+        let mut ch = ConsistentHash::builder().hasher(HashAlgorithm::Md5).ring_width(RingWidth::Bits64).build();
+        for i in 0..20 {
+            ch.add_node(&Node::new(format!("test_node_{}", i)), 40);
+        }
+        ch.set_hash_tag_extractor(hash_tag);
+        let compact = ch.compact();
+        assert_eq!(compact.size(), ch.size());
+        assert_eq!(compact.nodes.len(), 20);
+        for i in 0..500 {
+            let key = format!("user:{{{}}}:profile", i % 50);
+            assert_eq!(compact.get_node(key.clone()), ch.get_node_with_policy(key, LoadPolicy::Ignore));
+        }
+        assert!(compact.memory_footprint() * 3 < ch.memory_footprint());
+
+        assert_eq!(ConsistentHash::new().compact().get_node(String::from("test_key1")), None);
+    }
+}
@@ -0,0 +1,329 @@
+use std::fmt;
+
+use crate::{AverageBasis, ConsistentHash, HashAlgorithm, Node, PlacementVersion, RingWidth};
+
+// bumped whenever the layout below changes
+const FORMAT_VERSION: u8 = 5;
+
+/// Reasons `ConsistentHash::from_bytes` rejected its input.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The input ended in the middle of a field.
+    Truncated,
+    /// The input was written by an incompatible version of this crate.
+    UnsupportedVersion(u8),
+    /// The ring was built with a different hasher than the caller expects. Routing with it
+    /// would send keys to different nodes than the service that wrote it.
+    HasherMismatch { expected: String, found: String },
+    /// The ring width byte is not one this crate knows.
+    InvalidRingWidth(u8),
+    /// The placement version byte is not one this crate knows.
+    InvalidPlacementVersion(u8),
+    /// The average basis byte is not one this crate knows.
+    InvalidAverageBasis(u8),
+    /// A node name or tag is not valid UTF-8.
+    InvalidNodeName,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "input ends in the middle of a field"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            DecodeError::HasherMismatch { expected, found } => {
+                write!(f, "ring was built with hasher {}, expected {}", found, expected)
+            }
+            DecodeError::InvalidRingWidth(bytes) => write!(f, "invalid ring width of {} bytes", bytes),
+            DecodeError::InvalidPlacementVersion(id) => write!(f, "invalid placement version {}", id),
+            DecodeError::InvalidAverageBasis(id) => write!(f, "invalid average basis {}", id),
+            DecodeError::InvalidNodeName => write!(f, "node name or tag is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl ConsistentHash {
+    /// Encodes the ring's topology: hasher, ring width, placement version, load factor, average
+    /// basis and each node's tags, replica count or hand-placed positions, and capacity weight.
+    /// Loads, key tracking and runtime settings are left out; use `recompute_load` to rebuild
+    /// loads after decoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![FORMAT_VERSION];
+        let hasher_id = self.hasher_id().as_bytes();
+        bytes.push(hasher_id.len() as u8);
+        bytes.extend_from_slice(hasher_id);
+        bytes.push(self.ring_width.bytes() as u8);
+        bytes.push(self.placement_version.id());
+        bytes.extend_from_slice(&self.load_factor.to_be_bytes());
+        bytes.push(self.average_basis.id());
+
+        let mut names: Vec<&String> = self.replicas.keys().collect();
+        names.sort();
+        bytes.extend_from_slice(&(names.len() as u32).to_be_bytes());
+        for name in names {
+            put_str(&mut bytes, name);
+            bytes.extend_from_slice(&self.replicas[name].to_be_bytes());
+            bytes.extend_from_slice(&self.capacity_weight(name).to_be_bytes());
+            let tags = self.physical_node(name).map(|node| node.tags().clone()).unwrap_or_default();
+            bytes.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+            for (key, value) in tags.iter() {
+                put_str(&mut bytes, key);
+                put_str(&mut bytes, value);
+            }
+            // hand-placed positions, each ring width bytes long
+            let pinned = self.pinned_positions.get(name).map_or(&[][..], |positions| positions.as_slice());
+            bytes.extend_from_slice(&(pinned.len() as u32).to_be_bytes());
+            for position in pinned {
+                bytes.extend_from_slice(&self.position_bytes(*position));
+            }
+        }
+        bytes
+    }
+
+    /// Hash of the encoded topology. Rings built from the same nodes, hasher, ring width and
+    /// load settings share a fingerprint whichever process built them or in what order, so two
+    /// deployments can compare fingerprints to check that they route alike.
+    pub fn fingerprint(&self) -> u64 {
+        let digest = md5::compute(self.to_bytes());
+        u64::from_be_bytes([digest[0], digest[1], digest[2], digest[3], digest[4], digest[5], digest[6], digest[7]])
+    }
+
+    /// MD5 of the routing state itself: every ring position with the node holding it, each
+    /// node's replica count, the load factor and the average basis. Unlike `fingerprint`, which covers the settings
+    /// positions are derived from, this covers the positions, so it also catches rings that
+    /// agree on their settings but still route apart, like builds with different hash features
+    /// or a collision resolved differently. Loads are left out, so nodes in a cluster can gossip
+    /// it to spot topology drift.
+    pub fn state_hash(&self) -> [u8; 16] {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.nodes.len() as u32).to_be_bytes());
+        for (position, node) in self.nodes.iter() {
+            bytes.extend_from_slice(&self.position_bytes(*position));
+            put_str(&mut bytes, node.get_name());
+        }
+        let mut names: Vec<&String> = self.replicas.keys().collect();
+        names.sort();
+        bytes.extend_from_slice(&(names.len() as u32).to_be_bytes());
+        for name in names {
+            put_str(&mut bytes, name);
+            bytes.extend_from_slice(&self.replicas[name].to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.load_factor.to_be_bytes());
+        bytes.push(self.average_basis.id());
+        md5::compute(bytes).0
+    }
+
+    /// Rebuilds a ring written by `to_bytes`, rejecting it unless it was built with `hasher`.
+    pub fn from_bytes(bytes: &[u8], hasher: HashAlgorithm) -> Result<ConsistentHash, DecodeError> {
+        let mut reader = Reader { bytes };
+        let version = reader.u8()?;
+        if version != FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let id_len = reader.u8()? as usize;
+        let found = String::from_utf8_lossy(reader.take(id_len)?).into_owned();
+        if found != hasher.id() {
+            return Err(DecodeError::HasherMismatch { expected: hasher.id().to_string(), found });
+        }
+        let ring_width = match reader.u8()? {
+            4 => RingWidth::Bits32,
+            8 => RingWidth::Bits64,
+            16 => RingWidth::Bits128,
+            other => return Err(DecodeError::InvalidRingWidth(other)),
+        };
+        let placement_id = reader.u8()?;
+        let placement_version = PlacementVersion::from_id(placement_id).ok_or(DecodeError::InvalidPlacementVersion(placement_id))?;
+        let load_factor = reader.f64()?;
+        let basis_id = reader.u8()?;
+        let average_basis = AverageBasis::from_id(basis_id).ok_or(DecodeError::InvalidAverageBasis(basis_id))?;
+
+        let mut ch = ConsistentHash::builder()
+            .hasher(hasher)
+            .ring_width(ring_width)
+            .placement_version(placement_version)
+            .load_factor(load_factor)
+            .average_basis(average_basis)
+            .build();
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let replicas = reader.u32()?;
+            let weight = reader.f64()?;
+            let mut node = Node::new(name.clone());
+            for _ in 0..reader.u32()? {
+                let key = reader.string()?;
+                node = node.with_tag(&key, &reader.string()?);
+            }
+            let pinned: Vec<Vec<u8>> = (0..reader.u32()?)
+                .map(|_| reader.take(ring_width.bytes()).map(|position| position.to_vec()))
+                .collect::<Result<_, _>>()?;
+            if pinned.is_empty() {
+                ch.add_node(&node, replicas);
+            } else {
+                ch.add_node_at_positions(&node, &pinned);
+            }
+            if weight != 1.0 {
+                ch.set_capacity_weight(&name, weight);
+            }
+        }
+        Ok(ch)
+    }
+}
+
+// a length-prefixed UTF-8 string
+fn put_str(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+// walks the encoded fields front to back
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let field = self.take(4)?;
+        Ok(u32::from_be_bytes([field[0], field[1], field[2], field[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| DecodeError::InvalidNodeName)
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        let mut field = [0u8; 8];
+        field.copy_from_slice(self.take(8)?);
+        Ok(f64::from_be_bytes(field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ZONE_TAG;
+
+    fn ring() -> ConsistentHash {
+        let mut ch = ConsistentHash::builder()
+            .hasher(HashAlgorithm::Md5)
+            .ring_width(RingWidth::Bits64)
+            .placement_version(PlacementVersion::V2)
+            .load_factor(1.25)
+            .build();
+        for i in 0..4 {
+            ch.add_node(&Node::new(format!("test_node_{}", i)).with_tag(ZONE_TAG, &format!("zone_{}", i % 2)), i + 1);
+        }
+        ch.set_capacity_weight("test_node_2", 2.0);
+        ch.add_node_at_positions(&Node::new(String::from("pinned_node")), &[vec![0x80; 8]]);
+        ch.add_node(&Node::new(String::from("standby_node")).with_tag(ZONE_TAG, "zone_9"), 0);
+        ch
+    }
+
+    #[test]
+    fn round_trip() {
+        let ch = ring();
+        let decoded = ConsistentHash::from_bytes(&ch.to_bytes(), HashAlgorithm::Md5).unwrap();
+        assert_eq!(decoded.hasher_id(), "md5");
+        assert_eq!(decoded.nodes, ch.nodes);
+        assert_eq!(decoded.replicas, ch.replicas);
+        // tags are kept for nodes without positions too
+        assert_eq!(decoded.physical_nodes, ch.physical_nodes);
+        assert_eq!(decoded.physical_node("standby_node").unwrap().zone(), Some("zone_9"));
+        assert_eq!(decoded.capacity_weights, ch.capacity_weights);
+        assert_eq!(decoded.placement_version, PlacementVersion::V2);
+        assert_eq!(decoded.load_factor, 1.25);
+        assert_eq!(decoded.average_basis, AverageBasis::Physical);
+        assert_eq!(decoded.to_bytes(), ch.to_bytes());
+
+        let mut per_position = ring();
+        per_position.average_basis = AverageBasis::Virtual;
+        let decoded = ConsistentHash::from_bytes(&per_position.to_bytes(), HashAlgorithm::Md5).unwrap();
+        assert_eq!(decoded.average_basis, AverageBasis::Virtual);
+        assert_ne!(per_position.fingerprint(), ch.fingerprint());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let bytes = ring().to_bytes();
+        assert_eq!(ConsistentHash::from_bytes(&bytes[..bytes.len() - 1], HashAlgorithm::Md5).err(), Some(DecodeError::Truncated));
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 9;
+        assert_eq!(ConsistentHash::from_bytes(&wrong_version, HashAlgorithm::Md5).err(), Some(DecodeError::UnsupportedVersion(9)));
+
+        let mut other_hasher = vec![FORMAT_VERSION, 6];
+        other_hasher.extend_from_slice(b"sha256");
+        other_hasher.extend_from_slice(&bytes[5..]);
+        assert_eq!(
+            ConsistentHash::from_bytes(&other_hasher, HashAlgorithm::Md5).err(),
+            Some(DecodeError::HasherMismatch { expected: String::from("md5"), found: String::from("sha256") })
+        );
+
+        let mut unknown_placement = bytes.clone();
+        unknown_placement[6] = 9;
+        assert_eq!(
+            ConsistentHash::from_bytes(&unknown_placement, HashAlgorithm::Md5).err(),
+            Some(DecodeError::InvalidPlacementVersion(9))
+        );
+
+        // after the version, hasher id, width, placement and load factor
+        let mut unknown_basis = bytes.clone();
+        unknown_basis[15] = 9;
+        assert_eq!(ConsistentHash::from_bytes(&unknown_basis, HashAlgorithm::Md5).err(), Some(DecodeError::InvalidAverageBasis(9)));
+    }
+
+    #[test]
+    fn routing_survives_restarts() {
+        let ch = ring();
+        let restored = ConsistentHash::from_bytes(&ch.to_bytes(), HashAlgorithm::Md5).unwrap();
+        for i in 0..200 {
+            let key = format!("test_key{}", i);
+            assert_eq!(restored.get_node(key.clone()), ch.get_node(key));
+        }
+        assert_eq!(restored.fingerprint(), ch.fingerprint());
+
+        let mut changed = ring();
+        changed.add_node(&Node::new(String::from("test_node_9")), 1);
+        assert_ne!(changed.fingerprint(), ch.fingerprint());
+    }
+
+    #[test]
+    fn state_hash() {
+        let ch = ring();
+        let mut same = ring();
+        same.assign_key(String::from("test_key1")).unwrap();
+        assert_eq!(same.state_hash(), ch.state_hash(), "loads are not part of the state");
+
+        // the same node at positions of different widths
+        let mut wider = ConsistentHash::builder().hasher(HashAlgorithm::Md5).load_factor(1.25).build();
+        let mut narrower = ConsistentHash::builder().hasher(HashAlgorithm::Md5).ring_width(RingWidth::Bits32).load_factor(1.25).build();
+        for ring in [&mut wider, &mut narrower] {
+            ring.add_node(&Node::new(String::from("test_node_0")), 3);
+        }
+        assert_ne!(wider.state_hash(), narrower.state_hash());
+
+        let mut changed = ring();
+        changed.set_replicas("test_node_0", 2);
+        assert_ne!(changed.state_hash(), ch.state_hash());
+        let mut tighter = ring();
+        tighter.set_load_factor(1.0);
+        assert_ne!(tighter.state_hash(), ch.state_hash());
+        let mut per_position = ring();
+        per_position.average_basis = AverageBasis::Virtual;
+        assert_ne!(per_position.state_hash(), ch.state_hash());
+    }
+}
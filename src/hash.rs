@@ -0,0 +1,125 @@
+/// Digest used to place both nodes and keys on the ring.
+///
+/// The digest defines every ring position, so changing it moves almost every key. Pick one up
+/// front and use the same one everywhere a ring is built for the same cluster.
+///
+/// MD5 is fast and well spread but cryptographically broken: a client that controls key names
+/// can search for keys that all land next to the same node and overload it, something the
+/// bounded-load cap only softens. `Sha256` (behind the `sha256` feature) makes finding such keys
+/// impractical. Its digest is truncated to the same 16 bytes as MD5, which keeps the ring width
+/// unchanged. `XxHash` (behind the `xxhash` feature) is the fastest of the three but no harder to
+/// attack than MD5.
+///
+/// The default is picked at compile time by the `hash-md5` (the default), `hash-sha256` or
+/// `hash-xxhash` feature. Since the digest defines the ring, builds of this crate with different
+/// hash features place keys differently and must not route for the same cluster.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    Md5,
+    #[cfg(feature = "sha256")]
+    Sha256,
+    #[cfg(feature = "xxhash")]
+    XxHash,
+}
+
+#[cfg(any(
+    all(feature = "hash-md5", feature = "hash-sha256"),
+    all(feature = "hash-md5", feature = "hash-xxhash"),
+    all(feature = "hash-sha256", feature = "hash-xxhash"),
+))]
+compile_error!(
+    "only one of the `hash-md5`, `hash-sha256` and `hash-xxhash` features can be enabled; \
+     `hash-md5` is a default feature, so disable default features to pick another"
+);
+
+#[cfg(feature = "hash-sha256")]
+const DEFAULT: HashAlgorithm = HashAlgorithm::Sha256;
+#[cfg(feature = "hash-xxhash")]
+const DEFAULT: HashAlgorithm = HashAlgorithm::XxHash;
+// MD5 is also the fallback when default features are off and no hash feature is picked
+#[cfg(not(any(feature = "hash-sha256", feature = "hash-xxhash")))]
+const DEFAULT: HashAlgorithm = HashAlgorithm::Md5;
+
+impl Default for HashAlgorithm {
+    /// The digest selected by the crate's `hash-*` feature.
+    fn default() -> HashAlgorithm {
+        DEFAULT
+    }
+}
+
+impl HashAlgorithm {
+    /// Stable name of the digest, for checking that two rings agree on it. It never changes for
+    /// a given algorithm, so it is safe to persist.
+    pub fn id(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => "sha256",
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => "xxh64",
+        }
+    }
+
+    /// 16-byte digest of `data`.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Md5 => md5::compute(data).to_vec(),
+            #[cfg(feature = "sha256")]
+            HashAlgorithm::Sha256 => sha256::compute(data)[..16].to_vec(),
+            #[cfg(feature = "xxhash")]
+            HashAlgorithm::XxHash => {
+                // XXH64 only gives 8 bytes, so two seeds fill the 16
+                let mut digest = xxh64::compute(data, 0).to_be_bytes().to_vec();
+                digest.extend_from_slice(&xxh64::compute(data, 1).to_be_bytes());
+                digest
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sha256")]
+mod sha256 {
+    use sha2::{Digest, Sha256};
+
+    pub fn compute(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn hex(bytes: &[u8]) -> String {
+            bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+
+        #[test]
+        fn known_digests() {
+            assert_eq!(hex(&compute(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+            assert_eq!(hex(&compute(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+            assert_eq!(
+                hex(&compute(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+            );
+        }
+    }
+}
+
+#[cfg(feature = "xxhash")]
+mod xxh64 {
+    pub fn compute(data: &[u8], seed: u64) -> u64 {
+        xxhash_rust::xxh64::xxh64(data, seed)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn known_digests() {
+            assert_eq!(compute(b"", 0), 0xef46db3751d8e999);
+            assert_eq!(compute(b"abc", 0), 0x44bc2cf5ad770999);
+            assert_eq!(compute(b"Nobody inspects the spammish repetition", 0), 0xfbcea83c8a378bf1);
+        }
+    }
+}
@@ -1,32 +1,214 @@
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::ops::{BitOr, BitOrAssign};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use md5;
+mod anchor;
+mod compact;
+mod encoding;
+mod hash;
+mod manager;
+mod ratelimit;
+mod snapshot;
+
+pub use anchor::AnchorHash;
+pub use compact::CompactRing;
+pub use encoding::DecodeError;
+pub use hash::HashAlgorithm;
+pub use manager::RingManager;
+pub use ratelimit::RateLimiter;
+pub use snapshot::SnapshotRing;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Node {
     name: String,
+    tags: BTreeMap<String, String>,
 }
 
 impl Node {
     pub fn new(name: String) -> Node {
         Node{
             name,
+            tags: BTreeMap::new(),
         }
     }
 
+    /// Attaches a `key=value` tag, replacing any earlier value for `key`. Tags describe the node,
+    /// e.g. the zone it runs in, and never affect its ring positions, which come from the name
+    /// alone.
+    pub fn with_tag(mut self, key: &str, value: &str) -> Node {
+        self.tags.insert(key.to_string(), value.to_string());
+        self
+    }
+
     pub fn get_name(&self) -> &String {
         &self.name
     }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|value| value.as_str())
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// The failure domain (rack, availability zone) the node is in, from its `zone` tag.
+    pub fn zone(&self) -> Option<&str> {
+        self.tag(ZONE_TAG)
+    }
+}
+
+/// Tag naming a node's failure domain, see `Node::zone`.
+pub const ZONE_TAG: &str = "zone";
+
+/// Reasons a key could not be placed on the ring.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RingError {
+    /// The ring has no nodes, usually a configuration problem.
+    EmptyRing,
+    /// Every node is at its max allowed load.
+    Saturated,
+    /// The key is empty and the ring was built in strict mode.
+    EmptyKey,
+    /// The scan limit ran out before a node with room was found. Nodes further round the ring
+    /// may still have room.
+    ScanLimitExceeded,
+}
+
+impl fmt::Display for RingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RingError::EmptyRing => write!(f, "no nodes in the ring"),
+            RingError::Saturated => write!(f, "all nodes are at their max allowed load"),
+            RingError::EmptyKey => write!(f, "empty key rejected by strict mode"),
+            RingError::ScanLimitExceeded => write!(f, "gave up looking for a node under its max allowed load"),
+        }
+    }
+}
+
+impl std::error::Error for RingError {}
+
+/// Reasons `merge` refused to combine two rings.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MergeError {
+    /// The rings hash with different algorithms, so their positions don't line up.
+    HasherMismatch { ours: String, theirs: String },
+    /// The rings truncate positions to different widths.
+    RingWidthMismatch,
+    /// The rings derive replica positions from node names differently.
+    PlacementVersionMismatch,
+    /// Both rings have a node by this name, with different replica counts.
+    ReplicaConflict { name: String, ours: u32, theirs: u32 },
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::HasherMismatch { ours, theirs } => {
+                write!(f, "rings use different hashers: {} and {}", ours, theirs)
+            }
+            MergeError::RingWidthMismatch => write!(f, "rings use different ring widths"),
+            MergeError::PlacementVersionMismatch => write!(f, "rings use different placement versions"),
+            MergeError::ReplicaConflict { name, ours, theirs } => {
+                write!(f, "node {} has {} replicas in one ring and {} in the other", name, ours, theirs)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Reasons `try_remove_node` removed nothing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RemoveError {
+    /// The ring has no nodes at all.
+    EmptyRing,
+    /// No node by this name is in the ring.
+    NotFound(String),
+}
+
+impl fmt::Display for RemoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoveError::EmptyRing => write!(f, "no nodes in the ring"),
+            RemoveError::NotFound(name) => write!(f, "node {} is not in the ring", name),
+        }
+    }
+}
+
+impl std::error::Error for RemoveError {}
+
+/// Reasons `import_load` restored nothing.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ImportLoadError {
+    /// The saved loads name a node that is not in the ring.
+    UnknownNode(String),
+}
+
+impl fmt::Display for ImportLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportLoadError::UnknownNode(name) => write!(f, "saved load for node {} which is not in the ring", name),
+        }
+    }
 }
 
+impl std::error::Error for ImportLoadError {}
+
 #[derive(Clone)]
 pub struct ConsistentHash {
-    nodes: BTreeMap<Vec<u8>, Node>,
+    // positions are big-endian digest prefixes read as u128s, see `position_value`
+    nodes: BTreeMap<u128, Node>,
     replicas: HashMap<String, u32>,
+    // each physical node as last added, tags included, whether or not it holds any positions
+    physical_nodes: HashMap<String, Node>,
+    // positions more than one node hashes to, with every node claiming them
+    shared_positions: HashMap<u128, Vec<Node>>,
+    // positions of nodes placed by hand rather than by hashing their name
+    pinned_positions: HashMap<String, Vec<u128>>,
 
     load_per_node: HashMap<String, u64>,
     load_factor: f64,
+    average_basis: AverageBasis,
     total_load: u64,
+    capacity_weights: HashMap<String, f64>,
+    choice_window: usize,
+    scan_limit: Option<usize>,
+    saturation_policy: SaturationPolicy,
+    warmup_cap: u64,
+    node_states: HashMap<String, NodeState>,
+    overflow_node: Option<Node>,
+    overflow_load: u64,
+    // keys read from several nodes, with how many
+    hot_keys: HashMap<String, usize>,
+
+    half_life: Option<Duration>,
+    decayed_load: HashMap<String, f64>,
+    decayed_total: f64,
+    last_decay: Option<Instant>,
+
+    hasher: HashAlgorithm,
+    ring_width: RingWidth,
+    placement_version: PlacementVersion,
+    hash_tag_extractor: Option<Arc<HashTagExtractor>>,
+
+    diagnostics: Diagnostics,
+    // tracked keys with the node each went to and the load it added there
+    assignments: Option<HashMap<String, (String, u64)>>,
+    last_assigned: HashMap<String, Instant>,
+    rejection_log: Vec<String>,
+    rejections: u64,
+    topology_log: Vec<TopologyEvent>,
+    topology_sequence: u64,
+    subscribers: Vec<Sender<TopologyEvent>>,
+    metrics: Arc<dyn Metrics>,
+    strict: bool,
 }
 
 impl ConsistentHash {
@@ -34,221 +216,4978 @@ impl ConsistentHash {
         ConsistentHash{
             nodes: BTreeMap::new(),
             replicas: HashMap::new(),
+            physical_nodes: HashMap::new(),
+            shared_positions: HashMap::new(),
+            pinned_positions: HashMap::new(),
 
             load_per_node: HashMap::new(),
             load_factor: 1.0,
+            average_basis: AverageBasis::default(),
             total_load: 0,
+            capacity_weights: HashMap::new(),
+            choice_window: 1,
+            scan_limit: None,
+            saturation_policy: SaturationPolicy::Reject,
+            warmup_cap: 1,
+            node_states: HashMap::new(),
+            overflow_node: None,
+            overflow_load: 0,
+            hot_keys: HashMap::new(),
+
+            half_life: None,
+            decayed_load: HashMap::new(),
+            decayed_total: 0.0,
+            last_decay: None,
+
+            hasher: HashAlgorithm::default(),
+            ring_width: RingWidth::default(),
+            placement_version: PlacementVersion::default(),
+            hash_tag_extractor: None,
+
+            diagnostics: Diagnostics::NONE,
+            assignments: None,
+            last_assigned: HashMap::new(),
+            rejection_log: Vec::new(),
+            rejections: 0,
+            topology_log: Vec::new(),
+            topology_sequence: 0,
+            subscribers: Vec::new(),
+            metrics: Arc::new(NoopMetrics),
+            strict: false,
         }
     }
 
+    pub fn builder() -> ConsistentHashBuilder {
+        ConsistentHashBuilder::new()
+    }
+
     pub fn with_load_factor(load_factor: f64) -> ConsistentHash {
         let mut ch = ConsistentHash::new();
         ch.load_factor = load_factor;
-        return ch;
+        ch
     }
 
-    pub fn add_node(&mut self, node: &Node, num_replicas: u32) {
-        let name: &String = node.get_name();
-            let hash: Vec<u8> = md5::compute(name).to_vec();
+    /// Builds an empty ring placing nodes and keys with `hasher`. See `HashAlgorithm` for why
+    /// this has to be chosen before any nodes are added.
+    pub fn with_hasher(hasher: HashAlgorithm) -> ConsistentHash {
+        let mut ch = ConsistentHash::new();
+        ch.hasher = hasher;
+        ch
+    }
 
-            self.nodes.insert(hash, node.clone());
+    /// Builds a ring for a whole cluster in one go, giving each node the paired replica count.
+    pub fn from_weighted(nodes: &[(Node, u32)], load_factor: f64, hasher: HashAlgorithm) -> ConsistentHash {
+        let mut ch = ConsistentHash::with_hasher(hasher);
+        ch.load_factor = load_factor;
+        for (node, num_replicas) in nodes.iter() {
+            ch.add_node(node, *num_replicas);
+        }
+        ch
+    }
 
-        self.load_per_node.insert(name.clone(), 0);
+    /// Adds a node with `num_replicas` ring positions, one per replica, so a node added with 0
+    /// replicas is known to the ring but gets no keys. Adding a node that is already in the ring
+    /// moves it to the new replica count and keeps its load.
+    pub fn add_node(&mut self, node: &Node, num_replicas: u32) {
+        let name: &String = node.get_name();
+        if let Some(&old_replicas) = self.replicas.get(name) {
+            self.release_positions(name, self.node_hashes(name, old_replicas));
+            self.pinned_positions.remove(name);
+        }
+        self.load_per_node.entry(name.clone()).or_insert(0);
         self.replicas.insert(name.clone(), num_replicas);
-        for replica in 1..num_replicas {
-            let identifier: String = format!("{}-{}", name, replica);
-            let hash: Vec<u8> = md5::compute(identifier).to_vec();
+        self.physical_nodes.insert(name.clone(), node.clone());
+        self.place_positions(node, self.node_hashes(name, num_replicas));
+        self.metrics.on_add_node(name);
+        self.record_topology(TopologyChange::AddNode { node: node.clone(), replicas: num_replicas });
+    }
 
-            self.nodes.insert(hash, node.clone());
+    /// Adds a node at exactly the given ring positions instead of ones derived from its name,
+    /// for hand-tuned layouts or tests that need particular neighbours. Positions are cut or
+    /// zero-padded to the ring width. The node keeps them until it is removed or re-added with
+    /// `add_node`; `set_replicas` and `rebalance_replicas` leave it alone. Positions given more
+    /// than once, including ones that only match after cutting, count once towards its replicas.
+    /// Re-adding a node that is already in the ring keeps its load.
+    pub fn add_node_at_positions(&mut self, node: &Node, positions: &[Vec<u8>]) {
+        let name: &String = node.get_name();
+        if let Some(&old_replicas) = self.replicas.get(name) {
+            self.release_positions(name, self.node_hashes(name, old_replicas));
         }
+        let mut positions: Vec<u128> = positions.iter()
+            .map(|position| {
+                let mut position = position.clone();
+                position.resize(self.ring_width.bytes(), 0);
+                position_value(&position)
+            })
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        self.load_per_node.entry(name.clone()).or_insert(0);
+        self.replicas.insert(name.clone(), positions.len() as u32);
+        self.physical_nodes.insert(name.clone(), node.clone());
+        self.pinned_positions.insert(name.clone(), positions.clone());
+        let recorded = positions.iter().map(|position| self.position_bytes(*position)).collect();
+        self.place_positions(node, positions);
+        self.metrics.on_add_node(name);
+        self.record_topology(TopologyChange::AddNodeAtPositions { node: node.clone(), positions: recorded });
     }
 
-    pub fn get_node(&self, key: String) -> Option<Node> {
-        if self.nodes.is_empty() {
-            return None;
+    /// The positions `add_node(node, num_replicas)` would give a node, checked against the ring
+    /// without changing it. Replicas hashing to the same position, or to one another node
+    /// holds, leave the node with fewer positions than replicas; see `RingWidth` for how likely
+    /// that is.
+    pub fn verify_placement(&self, node: &Node, num_replicas: u32) -> PlacementReport {
+        let name = node.get_name();
+        let mut hashes = self.replica_hashes(name, num_replicas);
+        hashes.sort_unstable();
+        hashes.dedup();
+        let mut collisions: Vec<(Vec<u8>, Node)> = Vec::new();
+        let mut held = 0;
+        for hash in hashes.iter() {
+            match self.nodes.get(hash) {
+                Some(holder) if holder.get_name() != name => {
+                    // the smaller name keeps a shared position, as in `place_positions`
+                    if name < holder.get_name() {
+                        held += 1;
+                    }
+                    collisions.push((self.position_bytes(*hash), holder.clone()));
+                }
+                _ => held += 1,
+            }
         }
-        self.nearest_node_under_load(key)
+        PlacementReport { replicas: num_replicas, distinct: hashes.len(), held, collisions }
     }
 
-    fn nearest_node_under_load(&self, key: String) -> Option<Node> {
-        let hash: Vec<u8> = md5::compute(key).to_vec();
-        // using this since BTreeMap lower_bound has been marked as an experimental API currently.
-        let mut iter = self.nodes.range(hash..);
-        let mut count = 0;
-        loop {
-            if count > self.size() {
-                return None;
+    /// Adds a node with as many replicas as it takes to own about `target_fraction` of the
+    /// keyspace, given the positions the other nodes hold now, and returns that count.
+    ///
+    /// A node's share only grows as it gets more positions, so this binary searches the replica
+    /// count against the share its actual positions would own, returning the count landing
+    /// closest to the target. Counts go up to `MAX_FRACTION_REPLICAS`, which bounds fractions
+    /// close to 1. A target of 0 or less adds the node with no replicas. Later changes to the
+    /// other nodes shift the share; the replica count stays put.
+    pub fn add_node_for_fraction(&mut self, node: &Node, target_fraction: f64) -> u32 {
+        let name = node.get_name();
+        let num_replicas = if target_fraction > 0.0 {
+            let others: Vec<u128> = self.nodes.iter()
+                .filter(|(_hash, holder)| holder.get_name() != name)
+                .map(|(hash, _holder)| *hash)
+                .collect();
+            // double up to a count reaching the target, hashing no more positions than needed
+            let mut high = 1;
+            let mut candidates = self.replica_hashes(name, high);
+            while high < MAX_FRACTION_REPLICAS && owned_fraction(&others, &candidates) < target_fraction {
+                high = (high * 2).min(MAX_FRACTION_REPLICAS);
+                candidates = self.replica_hashes(name, high);
             }
-            let curr_node: Node;
-            if let Some((_k, node)) = iter.next() {
-                curr_node = node.clone();
+            let owned = |replicas: u32| owned_fraction(&others, &candidates[..replicas as usize]);
+
+            // smallest count reaching the target, then whichever of it and the one below is closer
+            let mut low = 1;
+            while low < high {
+                let middle = low + (high - low) / 2;
+                if owned(middle) < target_fraction {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            if low > 1 && target_fraction - owned(low - 1) < owned(low) - target_fraction {
+                low - 1
             } else {
-                // initialize to the first node in the tree
-                iter = self.nodes.range(vec![0]..);
-                continue;
+                low
+            }
+        } else {
+            0
+        };
+        self.add_node(node, num_replicas);
+        num_replicas
+    }
+
+    // puts a node on the ring at `hashes`. A position another node also hashes to goes to the
+    // node with the smaller name, so the ring comes out the same whatever order nodes are added
+    // in, and both claims are kept so the loser gets the position back if the winner leaves.
+    fn place_positions(&mut self, node: &Node, hashes: Vec<u128>) {
+        for hash in hashes {
+            let holder = match self.nodes.get(&hash) {
+                Some(holder) if holder.get_name() != node.get_name() => holder.clone(),
+                _ => {
+                    self.nodes.insert(hash, node.clone());
+                    continue;
+                }
+            };
+            let claimants = self.shared_positions.entry(hash).or_default();
+            if !claimants.contains(&holder) {
+                claimants.push(holder.clone());
             }
-            if self.check_load(curr_node.get_name().to_string()) {
-                return Some(curr_node);
+            if !claimants.contains(node) {
+                claimants.push(node.clone());
+            }
+            if node.get_name() < holder.get_name() {
+                self.nodes.insert(hash, node.clone());
             }
-            count += 1;
         }
     }
 
-    // checks if the node is below the max allowed load value
-    fn check_load(&self, node_name: String) -> bool {
-        let tot_nodes = self.size();
-        if tot_nodes == 0 {
-            return false;
+    // takes a node off the ring at `hashes`, handing any position it shared to the remaining
+    // claimant with the smallest name
+    fn release_positions(&mut self, name: &str, hashes: Vec<u128>) {
+        for hash in hashes {
+            if self.nodes.get(&hash).is_some_and(|holder| holder.get_name() == name) {
+                self.nodes.remove(&hash);
+            }
+            let claimants = match self.shared_positions.get_mut(&hash) {
+                None => continue,
+                Some(claimants) => claimants,
+            };
+            claimants.retain(|claimant| claimant.get_name() != name);
+            if let Some(next) = claimants.iter().min_by(|a, b| a.get_name().cmp(b.get_name())) {
+                self.nodes.entry(hash).or_insert_with(|| next.clone());
+            }
+            if claimants.len() < 2 {
+                self.shared_positions.remove(&hash);
+            }
         }
-        let mut avg_load: f64 = self.total_load as f64 / tot_nodes as f64;
-        if avg_load == 0.0 {
-            avg_load = 1.0;
+    }
+
+    // ring positions for a node, one per replica. Replica identifiers separate the name from the
+    // replica number with a 0xFF byte, which never occurs in UTF-8, so they can't collide with
+    // another node's name (`a` replica 1 vs a node `a-1`). Nodes added with explicit positions
+    // always get those, whatever the replica count.
+    fn node_hashes(&self, name: &str, num_replicas: u32) -> Vec<u128> {
+        if let Some(positions) = self.pinned_positions.get(name) {
+            return positions.clone();
         }
-        let max_allowed_load: u64 = (avg_load * self.load_factor).ceil() as u64;
-        
-        match self.load_per_node.get(&node_name) {
-            None => false,
-            Some(&val) => (val + 1) <= max_allowed_load,
+        self.replica_hashes(name, num_replicas)
+    }
+
+    // positions derived from a node's name, one per replica
+    fn replica_hashes(&self, name: &str, num_replicas: u32) -> Vec<u128> {
+        let mut hashes: Vec<u128> = Vec::with_capacity(num_replicas as usize);
+        for replica in 0..num_replicas {
+            let mut identifier: Vec<u8> = name.as_bytes().to_vec();
+            identifier.push(REPLICA_SEPARATOR);
+            match self.placement_version {
+                PlacementVersion::V1 => identifier.extend_from_slice(replica.to_string().as_bytes()),
+                PlacementVersion::V2 => identifier.extend_from_slice(&replica.to_be_bytes()),
+            }
+            hashes.push(self.digest(&identifier));
         }
+        hashes
     }
 
-    pub fn assign_key(&mut self, key: String) {
-        if let Some(node) = self.get_node(key) {
-            let node_name = node.get_name();
-            let load = match self.load_per_node.get(node_name) {
-                None => 0,
-                Some(&val) => val,
-            };
-            self.load_per_node.insert(node_name.to_string(), load + 1);
-            self.total_load += 1;
-            return;
+    // a physical node as it was added, tags included, even with no ring positions
+    fn physical_node(&self, name: &str) -> Option<&Node> {
+        self.physical_nodes.get(name)
+    }
+
+    // ring position of a key, hashing only its tag when an extractor is set
+    fn key_hash(&self, key: &str) -> u128 {
+        self.digest(self.routed_key(key).as_bytes())
+    }
+
+    // the part of a key it is routed by: its hash tag if it has a non-empty one
+    fn routed_key<'a>(&self, key: &'a str) -> &'a str {
+        routed_key(self.hash_tag_extractor.as_deref(), key)
+    }
+
+    // ring position for raw bytes: the hasher's digest cut down to the ring width
+    fn digest(&self, data: &[u8]) -> u128 {
+        ring_position(self.hasher, self.ring_width, data)
+    }
+
+    // a ring position as the ring width's worth of big-endian bytes, the form the public API
+    // deals in
+    fn position_bytes(&self, position: u128) -> Vec<u8> {
+        position.to_be_bytes()[..self.ring_width.bytes()].to_vec()
+    }
+
+    /// Stable identifier of the ring's hasher, e.g. "md5". Two rings route the same keys to the
+    /// same nodes only if this, the ring width and the topology all match.
+    pub fn hasher_id(&self) -> &str {
+        self.hasher.id()
+    }
+
+    /// Routes keys by the part of them `extractor` returns, so related keys land on the same
+    /// node. An empty result falls back to hashing the whole key. Pass `hash_tag` for Redis
+    /// style `{...}` tags.
+    pub fn set_hash_tag_extractor(&mut self, extractor: impl Fn(&str) -> &str + Send + Sync + 'static) {
+        self.hash_tag_extractor = Some(Arc::new(extractor));
+    }
+
+    /// Node a key should go to: the first one clockwise from its hash that is under the max
+    /// allowed load. An empty key is hashed like any other (MD5 of no input is a valid digest)
+    /// and always lands in the same place, unless the ring was built in strict mode, in which
+    /// case it is rejected.
+    pub fn get_node(&self, key: String) -> Option<Node> {
+        self.try_get_node(key).ok()
+    }
+
+    /// Routes a key like `get_node`, but only to a node with at least `min_headroom` units left
+    /// under its max allowed load, e.g. to place a batch of known size in one go before
+    /// committing it with `assign_key_with_cost`. None if no node has that much room, even
+    /// with `SaturationPolicy::LeastLoaded`.
+    pub fn get_node_with_min_headroom(&self, key: String, min_headroom: u64) -> Option<Node> {
+        self.resolve_with_cost(&key, min_headroom, LoadPolicy::Strict)
+            .ok()
+            .filter(|node| self.check_load(node.get_name(), min_headroom))
+            .cloned()
+    }
+
+    /// The first node clockwise of `key` carrying every tag in `required_tags` with the given
+    /// value, e.g. to send GPU work only to `("gpu", "true")` nodes while keys still hash stably
+    /// among them. Unhealthy nodes are skipped and load is ignored. None if no node matches.
+    pub fn get_node_filtered(&self, key: String, required_tags: &[(&str, &str)]) -> Option<Node> {
+        let hash: u128 = self.key_hash(&key);
+        self.clockwise(hash)
+            .map(|(_hash, node)| node)
+            .find(|node| {
+                self.node_state(node.get_name()) != Some(NodeState::Unhealthy)
+                    && required_tags.iter().all(|(key, value)| node.tag(key) == Some(*value))
+            })
+            .cloned()
+    }
+
+    /// Routes a key like `get_node`, then hands the node to `resolver`, e.g. to check a
+    /// connection out of a pool. The lookup happens right away, so the returned future does not
+    /// borrow the ring and later ring changes do not affect it. Resolves to None if no node was
+    /// found or the resolver gave up.
+    pub fn get_node_async<R: NodeResolver>(
+        &self,
+        key: String,
+        resolver: &R,
+    ) -> impl Future<Output = Option<R::Resolved>> {
+        let pending = self.get_node(key).map(|node| resolver.resolve(node));
+        async move {
+            match pending {
+                None => None,
+                Some(pending) => pending.await,
+            }
         }
-        println!("ERR: no node available to be assigned")
     }
 
-    pub fn remove_node(& mut self, name: String) {
+    /// Like `get_node`, but tells an empty ring apart from one where every node is at capacity.
+    pub fn try_get_node(&self, key: String) -> Result<Node, RingError> {
+        self.resolve(key, LoadPolicy::Strict).cloned()
+    }
+
+    /// Like `get_node`, but lets this one lookup use a different bounded-load policy than the
+    /// ring's, e.g. to place a high-priority request on a node that is already at its cap.
+    pub fn get_node_with_policy(&self, key: String, policy: LoadPolicy) -> Option<Node> {
+        self.resolve(key, policy).ok().cloned()
+    }
+
+    /// The keys among `keys` that `get_node` currently routes to `node_name`, in their original
+    /// order. Handy for building a per-node work list or checking that hash tags co-locate.
+    pub fn filter_keys_for_node<'a>(&self, keys: &'a [String], node_name: &str) -> Vec<&'a str> {
+        keys.iter()
+            .filter(|key| self.with_node(key.to_string(), |node| node.get_name() == node_name).unwrap_or(false))
+            .map(|key| key.as_str())
+            .collect()
+    }
+
+    /// Like `get_node`, but also returns the node's current load, read in the same call.
+    pub fn get_node_with_load(&self, key: String) -> Option<(Node, u64)> {
+        self.with_node(key, |node| (node.clone(), self.load_per_node.get(node.get_name()).copied().unwrap_or(0)))
+    }
+
+    /// Resolves a key and hands the node to `f` by reference, avoiding the clone `get_node`
+    /// makes. Returns None, without calling `f`, when no node can take the key.
+    pub fn with_node<R>(&self, key: String, f: impl FnOnce(&Node) -> R) -> Option<R> {
+        self.resolve(key, LoadPolicy::Strict).ok().map(f)
+    }
+
+    fn resolve(&self, key: String, policy: LoadPolicy) -> Result<&Node, RingError> {
+        self.resolve_with_cost(&key, 1, policy)
+    }
+
+    // resolve for a key adding `cost` to its node's load
+    fn resolve_with_cost(&self, key: &str, cost: u64, policy: LoadPolicy) -> Result<&Node, RingError> {
+        if self.strict && key.is_empty() {
+            return Err(RingError::EmptyKey);
+        }
         if self.nodes.is_empty() {
-            return;
+            return Err(RingError::EmptyRing);
         }
-        let node_name = name.clone();
-        let num_replicas = match self.replicas.get(&node_name) {
-            None => return,
-            Some(&val) => val
-        };
-        let hash: Vec<u8> = md5::compute(&node_name).to_vec();
-        self.nodes.remove(&hash);
-        for replica in 1..num_replicas {
-            let identifier: String = format!("{}-{}", name, replica);
-            let hash: Vec<u8> = md5::compute(identifier).to_vec();
+        self.nearest_node_under_load(self.key_hash(key), cost, policy)
+    }
 
-            self.nodes.remove(&hash);
+    // walks clockwise from the key and picks the least-loaded of the first `choice_window`
+    // distinct healthy nodes with room for `cost` more load under the max allowed load, the
+    // earliest one winning ties. Unhealthy nodes are skipped and the first degraded node with
+    // room is only taken when no healthy one has any. Gives up after `scan_limit` positions.
+    fn nearest_node_under_load(&self, hash: u128, cost: u64, policy: LoadPolicy) -> Result<&Node, RingError> {
+        let mut candidates: Vec<&str> = Vec::new();
+        let mut best: Option<(&Node, f64)> = None;
+        let mut degraded: Option<&Node> = None;
+        for (count, (_hash, curr_node)) in self.clockwise(hash).enumerate() {
+            if self.scan_limit.is_some_and(|limit| count >= limit) {
+                return best.map(|(node, _)| node).or(degraded).ok_or(RingError::ScanLimitExceeded);
+            }
+            let name = curr_node.get_name();
+            let state = self.node_state(name).unwrap_or_default();
+            if state == NodeState::Unhealthy
+                || candidates.contains(&name.as_str())
+                || !self.check_load_with_policy(name, cost, policy) {
+                continue;
+            }
+            if state == NodeState::Degraded {
+                degraded.get_or_insert(curr_node);
+                continue;
+            }
+            let load = self.effective_load(name);
+            if best.is_none_or(|(_, best_load)| load < best_load) {
+                best = Some((curr_node, load));
+            }
+            candidates.push(name);
+            if candidates.len() >= self.choice_window {
+                break;
+            }
+        }
+        match best.map(|(node, _)| node).or(degraded) {
+            Some(node) => Ok(node),
+            None if self.saturation_policy == SaturationPolicy::LeastLoaded => {
+                self.least_loaded().ok_or(RingError::Saturated)
+            }
+            None => Err(RingError::Saturated),
         }
-        self.total_load -= self.load_per_node[&node_name];
-        self.load_per_node.remove(&node_name);
+    }
 
-        self.replicas.remove(&name);
+    // the physical node carrying the least load that still takes keys, by name among equals
+    fn least_loaded(&self) -> Option<&Node> {
+        let mut names: Vec<&String> = self.replicas.keys()
+            .filter(|name| self.node_state(name) != Some(NodeState::Unhealthy))
+            .collect();
+        names.sort_by(|a, b| self.effective_load(a).total_cmp(&self.effective_load(b)).then(a.cmp(b)));
+        names.into_iter().find_map(|name| self.physical_node(name))
     }
 
-    pub fn size(&self) -> usize {
-        self.nodes.len()
+    /// What lookups do when every node is at its max allowed load. See `SaturationPolicy`.
+    pub fn set_saturation_policy(&mut self, policy: SaturationPolicy) {
+        self.saturation_policy = policy;
     }
 
-    pub fn list_nodes(&self) -> Option<Vec<Node>> {
-        if self.nodes.is_empty() {
-            return None;
+    /// Every ring position once, clockwise from the first one at or after `start` and wrapping
+    /// past the top of the ring, with the node holding it. `start` is read like the positions of
+    /// `owner_of_range`. This is the walk every lookup is built on, for custom placement policies
+    /// that need something the crate doesn't offer. It ignores load and node states.
+    pub fn range_from(&self, start: &[u8]) -> impl Iterator<Item = (Vec<u8>, &Node)> {
+        self.clockwise(position_value(start)).map(|(hash, node)| (self.position_bytes(*hash), node))
+    }
+
+    // ring positions once around, clockwise from `hash`
+    fn clockwise(&self, hash: u128) -> impl Iterator<Item = (&u128, &Node)> {
+        self.nodes.range(hash..).chain(self.nodes.range(..hash))
+    }
+
+    /// Marks a node healthy, degraded or unhealthy for routing, e.g. from health check results.
+    /// The node keeps its ring positions and load, so a node that recovers takes back exactly the
+    /// keys it had. Returns false if the node is not in the ring.
+    pub fn set_node_state(&mut self, name: &str, state: NodeState) -> bool {
+        if !self.replicas.contains_key(name) {
+            return false;
         }
-        Some(self.nodes.values().cloned().collect::<Vec<_>>())
+        match state {
+            NodeState::Healthy => self.node_states.remove(name),
+            _ => self.node_states.insert(name.to_string(), state),
+        };
+        true
     }
-}
 
-impl Default for ConsistentHash {
-    fn default() -> Self {
-        Self::new()
+    /// A node's routing state, or None if the node is unknown.
+    pub fn node_state(&self, name: &str) -> Option<NodeState> {
+        if !self.replicas.contains_key(name) {
+            return None;
+        }
+        Some(self.node_states.get(name).copied().unwrap_or_default())
     }
-}
 
+    /// Whether a node can take `additional` more units of load without going over its max
+    /// allowed load, e.g. for admission control ahead of a batch. False for unknown nodes.
+    pub fn check_load(&self, name: &str, additional: u64) -> bool {
+        self.check_load_with_policy(name, additional, LoadPolicy::Strict)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// How many distinct nodes `assign_key` could place `key` on right now: the nodes it would
+    /// reach walking clockwise that are not unhealthy and still have room for one more key. This
+    /// is the key's failover headroom, which `get_node` alone does not show. Without a scan limit
+    /// the walk covers the whole ring, so every key gets the same count; with one, only the nodes
+    /// within the limit's reach of the key count.
+    pub fn failover_depth(&self, key: String) -> usize {
+        let hash = self.key_hash(&key);
+        let limit = self.scan_limit.unwrap_or(usize::MAX);
+        let mut reachable: Vec<&str> = Vec::new();
+        for (_hash, node) in self.clockwise(hash).take(limit) {
+            let name = node.get_name().as_str();
+            if !reachable.contains(&name)
+                && self.node_state(name) != Some(NodeState::Unhealthy)
+                && self.check_load(name, 1) {
+                reachable.push(name);
+            }
+        }
+        reachable.len()
+    }
 
-    fn nodes_fixture(n: usize) -> Vec<Node> {
-        let mut test_nodes: Vec<Node> = Vec::<Node>::new();
-        for i in 0..n {
-            test_nodes.push(Node::new(format!("test_node_{}", i)));
+    // checks if the node stays within the max allowed load after `additional` more units
+    fn check_load_with_policy(&self, node_name: &str, additional: u64, policy: LoadPolicy) -> bool {
+        let tot_nodes = self.size();
+        if tot_nodes == 0 {
+            return false;
+        }
+        let load_factor = match policy {
+            LoadPolicy::Strict => self.load_factor,
+            LoadPolicy::Relaxed(load_factor) => load_factor,
+            LoadPolicy::Ignore => return self.load_per_node.contains_key(node_name),
+        };
+        if self.half_life.is_some() {
+            return self.check_decayed_load(node_name, additional, load_factor);
+        }
+        let max_allowed_load: u64 = self.max_allowed_load(node_name, load_factor);
+        match self.load_per_node.get(node_name) {
+            None => false,
+            Some(&val) => val.saturating_add(additional) <= max_allowed_load,
         }
-        return test_nodes;
     }
 
-    fn setup(nodes: Vec<Node>, replica_count: u32, load_factor: f64) -> ConsistentHash {
-        let mut ch: ConsistentHash = ConsistentHash::with_load_factor(load_factor);
+    // the bounded-load cap: the average load scaled by the load factor and
+    // the node's capacity weight, and never below the weighted warmup cap
+    fn max_allowed_load(&self, node_name: &str, load_factor: f64) -> u64 {
+        let weight = self.capacity_weight(node_name);
+        let cap = (self.average_load() * load_factor * weight).ceil();
+        cap.max((self.warmup_cap as f64 * weight).ceil()) as u64
+    }
 
-        for node in nodes.iter() {
-            ch.add_node(&node, replica_count);
+    // average load per node holding positions or per position, depending on the average basis,
+    // taken as 1 on an idle ring so the first keys have a cap to fit under
+    fn average_load(&self) -> f64 {
+        let avg_load: f64 = self.total_load as f64 / self.average_count() as f64;
+        if avg_load == 0.0 {
+            return 1.0;
         }
-        ch
+        avg_load
     }
 
-    #[test]
-    fn add_nodes() {
-        let nodes_count = 5;
-        let test_nodes = nodes_fixture(nodes_count);
-        let ch = setup(test_nodes, 3, 1.0);
-
-        let ch_size = ch.size();
-        assert!(ch_size == nodes_count * 3, "count mismatch. expected: {}, actual: {}", nodes_count * 3, ch_size);    
+    // how many the load is averaged over for the bounded-load cap under the average basis
+    fn average_count(&self) -> usize {
+        match self.average_basis {
+            AverageBasis::Physical => self.replicas.values().filter(|&&replicas| replicas > 0).count(),
+            AverageBasis::Virtual => self.size(),
+        }
     }
 
-    #[test]
-    fn remove_nodes() {
-        let nodes_count = 7;
-        let test_nodes = nodes_fixture(nodes_count);
-        let mut ch = setup(test_nodes, 5, 1.0);
+    fn capacity_weight(&self, node_name: &str) -> f64 {
+        self.capacity_weights.get(node_name).copied().unwrap_or(1.0)
+    }
 
-        let mut ch_size = ch.size();
-        assert!(ch_size == nodes_count * 5, "count mismatch after add_node. expected: {}, actual: {}", nodes_count * 3, ch_size);    
+    /// Scales a node's bounded-load cap, so a node with weight 2.0 may carry twice the load of
+    /// a node with the default weight of 1.0 before it is skipped. This is independent of the
+    /// replica count, which decides how much of the keyspace the node owns. Returns false if
+    /// the node is not in the ring.
+    pub fn set_capacity_weight(&mut self, name: &str, weight: f64) -> bool {
+        if !self.replicas.contains_key(name) {
+            return false;
+        }
+        self.capacity_weights.insert(name.to_string(), weight);
+        true
+    }
 
-        ch.remove_node("non_existant".to_string());
-        ch_size = ch.size();
-        assert!(ch_size == nodes_count * 5, "count mismatch after remove_node on non_existant. expected: {}, actual: {}", nodes_count * 5, ch_size);
+    /// How many more keys each physical node can take before hitting the bounded-load cap.
+    /// Negative values mean the node is over the cap, e.g. after lowering the load factor.
+    pub fn headroom(&self) -> HashMap<String, i64> {
+        if self.nodes.is_empty() {
+            return self.load_per_node.keys().map(|name| (name.clone(), 0)).collect();
+        }
+        self.load_per_node.iter()
+            .map(|(name, &load)| (name.clone(), self.max_allowed_load(name, self.load_factor) as i64 - load as i64))
+            .collect()
+    }
 
-        ch.remove_node("test_node_3".to_string());
-        ch_size = ch.size();
-        assert!(ch_size == (nodes_count - 1) * 5, "count mismatch after remove_node. expected: {}, actual: {}", (nodes_count - 1) * 5, ch_size);
+    /// Physical nodes with their headroom, emptiest first and by name among equals, for
+    /// capacity-first placement such as bin-packing that bypasses the hash.
+    pub fn nodes_by_capacity(&self) -> Vec<(Node, i64)> {
+        let mut nodes: Vec<(Node, i64)> = self.headroom().into_iter()
+            .filter_map(|(name, headroom)| Some((self.physical_node(&name)?.clone(), headroom)))
+            .collect();
+        nodes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.get_name().cmp(b.0.get_name())));
+        nodes
     }
 
-    #[test]
-    fn get_nodes() {
-        let nodes_count = 7;
-        let test_nodes = nodes_fixture(nodes_count);
-        let mut ch = setup(test_nodes, 3, 1.0);
+    // same cap as check_load, computed over decayed load. The loads are fractional here, so a
+    // node is accepted while strictly below the cap before the last unit, which matches
+    // `val + additional <= cap` for integers.
+    fn check_decayed_load(&self, node_name: &str, additional: u64, load_factor: f64) -> bool {
+        if !self.load_per_node.contains_key(node_name) {
+            return false;
+        }
+        let weight = self.capacity_weight(node_name);
+        let mut avg_load: f64 = self.decayed_total / self.average_count() as f64;
+        if avg_load == 0.0 {
+            avg_load = 1.0;
+        }
+        let max_allowed_load = (avg_load * load_factor * weight).ceil().max((self.warmup_cap as f64 * weight).ceil());
+        self.effective_load(node_name) + (additional as f64 - 1.0) < max_allowed_load
+    }
 
-        let mut matched_node = ch.get_node(String::from("test_key1")).unwrap();
-        assert_eq!(matched_node, Node::new(String::from("test_node_1")));
+    // the load used for placement decisions: decayed load once a half-life is set, the plain
+    // assignment count otherwise
+    fn effective_load(&self, node_name: &str) -> f64 {
+        if self.half_life.is_some() {
+            return self.decayed_load.get(node_name).copied().unwrap_or(0.0);
+        }
+        self.load_per_node.get(node_name).copied().unwrap_or(0) as f64
+    }
 
-        ch.add_node(&Node::new(String::from("test_node_8")), 3);
-        matched_node = ch.get_node(String::from("test_key1")).unwrap();
-        assert_eq!(matched_node, Node::new(String::from("test_node_1")));
+    /// Makes placement decisions use load that halves every `half_life`, turning the bounded-load
+    /// cap into a cap on recent request rate. Only `assign_key_with_decay` feeds the decayed load,
+    /// so a ring should use one mode or the other; `load_per_node` keeps counting plain
+    /// assignments either way.
+    pub fn set_half_life(&mut self, half_life: Duration) {
+        self.half_life = Some(half_life);
     }
 
-    #[test]
-    fn assign_key() {
-        let nodes_count = 3;
-        let test_nodes = nodes_fixture(nodes_count);
-        let mut ch = setup(test_nodes, 0, 1.0);
+    /// Decayed load of a node as of the last `assign_key_with_decay` call, or None if the node is
+    /// unknown.
+    pub fn decayed_load(&self, name: &str) -> Option<f64> {
+        if !self.load_per_node.contains_key(name) {
+            return None;
+        }
+        Some(self.decayed_load.get(name).copied().unwrap_or(0.0))
+    }
 
-        let matched_node = ch.get_node(String::from("test_key1")).unwrap();
-        assert_eq!(matched_node, Node::new(String::from("test_node_1")));
+    /// Assigns a key counting it as one unit of decaying load at `now`. Without a half-life set
+    /// this is the same as `assign_key`. Rejections reach the metrics hooks and rejection log
+    /// like any other.
+    pub fn assign_key_with_decay(&mut self, key: String, now: Instant) -> Result<(), RingError> {
+        if self.half_life.is_none() {
+            return self.assign_key(key).map(|_| ());
+        }
+        self.decay_to(now);
+        match self.try_get_node(key.clone()) {
+            Ok(node) => {
+                self.record_decayed_assignment(node.get_name(), now);
+                Ok(())
+            }
+            Err(err) => {
+                self.record_rejection(key);
+                Err(err)
+            }
+        }
+    }
 
-        assert_eq!(ch.total_load, 0);
+    // counts one unit of decaying load against a node at `now`
+    fn record_decayed_assignment(&mut self, name: &str, now: Instant) {
+        *self.decayed_load.entry(name.to_string()).or_insert(0.0) += 1.0;
+        self.decayed_total += 1.0;
+        if self.diagnostics.contains(Diagnostics::TIMESTAMPS) {
+            self.last_assigned.insert(name.to_string(), now);
+        }
+    }
 
-        ch.assign_key(String::from("test_key1"));
-        assert_eq!(ch.total_load, 1);
-        ch.assign_key(String::from("test_key2"));
-        assert_eq!(ch.total_load, 2);
-        ch.assign_key(String::from("test_key3"));
+    // scales every decayed load down by the half-lives elapsed since the last decay. Instants
+    // earlier than the last decay leave the loads untouched.
+    fn decay_to(&mut self, now: Instant) {
+        let half_life = match self.half_life {
+            None => return,
+            Some(half_life) => half_life,
+        };
+        let last = match self.last_decay {
+            None => {
+                self.last_decay = Some(now);
+                return;
+            }
+            Some(last) => last,
+        };
+        if now <= last {
+            return;
+        }
+        let factor = 0.5f64.powf(now.duration_since(last).as_secs_f64() / half_life.as_secs_f64());
+        for load in self.decayed_load.values_mut() {
+            *load *= factor;
+        }
+        self.decayed_total *= factor;
+        self.last_decay = Some(now);
+    }
+
+    /// Places a key on the node `get_node` picks and counts it towards that node's load. With key
+    /// tracking on, assigning a key that is already assigned changes nothing and reports the
+    /// node it is on, so retried deliveries count once until `unassign_key`.
+    pub fn assign_key(&mut self, key: String) -> Result<AssignOutcome, RingError> {
+        if let Some(outcome) = self.already_assigned(&key) {
+            return Ok(outcome);
+        }
+        match self.try_get_node(key.clone()) {
+            Ok(node) => Ok(self.record_assignment(node, Some(key), 1)),
+            Err(err) => {
+                self.record_rejection(key);
+                Err(err)
+            }
+        }
+    }
+
+    /// `assign_key`, returning the node with its load before and after the key, for accounting
+    /// that aggregates deltas downstream without a second lookup racing other assignments.
+    pub fn assign_key_returning_delta(&mut self, key: String) -> Result<(Node, u64, u64), RingError> {
+        if let Some(outcome) = self.already_assigned(&key) {
+            return Ok((outcome.node, outcome.new_load, outcome.new_load));
+        }
+        let outcome = self.assign_key(key)?;
+        Ok((outcome.node, outcome.new_load - 1, outcome.new_load))
+    }
+
+    /// `assign_key` for keys of varying cost: `cost_fn` estimates what the key adds to its node's
+    /// load, e.g. its expected request rate or size, and the key goes to the first node with
+    /// room for all of it under the bounded-load cap, as `check_load` with that much extra load
+    /// would tell. A tracked key gives back its whole cost on `unassign_key`.
+    pub fn assign_key_with_cost(
+        &mut self,
+        key: String,
+        cost_fn: impl Fn(&str) -> u64,
+    ) -> Result<AssignOutcome, RingError> {
+        if let Some(outcome) = self.already_assigned(&key) {
+            return Ok(outcome);
+        }
+        let cost = cost_fn(&key);
+        match self.resolve_with_cost(&key, cost, LoadPolicy::Strict).cloned() {
+            Ok(node) => Ok(self.record_assignment(node, Some(key), cost)),
+            Err(err) => {
+                self.record_rejection(key);
+                Err(err)
+            }
+        }
+    }
+
+    /// Routes an integer key by the digest of its 8 big-endian bytes, without formatting it into
+    /// a string first. Integer keys live in their own keyspace: `7` and `"7"` can land on
+    /// different nodes, and hash tag extraction does not apply.
+    pub fn get_node_u64(&self, key: u64) -> Option<Node> {
+        self.resolve_u64(key).ok().cloned()
+    }
+
+    /// `assign_key` for integer keys, routed like `get_node_u64`. Integer keys are not tracked for
+    /// `unassign_key`; rejections reach the metrics hooks and rejection log in decimal.
+    pub fn assign_key_u64(&mut self, key: u64) -> Result<AssignOutcome, RingError> {
+        match self.resolve_u64(key).cloned() {
+            Ok(node) => Ok(self.record_assignment(node, None, 1)),
+            Err(err) => {
+                self.record_rejection(key.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    /// Routes a key like `get_node`, but by the digest of `salt` and the key together, so each
+    /// salt gives its own stable placement on the same ring, e.g. to shadow a slice of traffic
+    /// under an experimental layout. The salt is length-prefixed, so no salt and key pair can
+    /// hash like a different pair with the same concatenation. Hash tags still apply to the key.
+    pub fn get_node_salted(&self, key: String, salt: &[u8]) -> Option<Node> {
+        if (self.strict && key.is_empty()) || self.nodes.is_empty() {
+            return None;
+        }
+        let mut input = (salt.len() as u64).to_be_bytes().to_vec();
+        input.extend_from_slice(salt);
+        input.extend_from_slice(self.routed_key(&key).as_bytes());
+        self.nearest_node_under_load(self.digest(&input), 1, LoadPolicy::Strict).ok().cloned()
+    }
+
+    fn resolve_u64(&self, key: u64) -> Result<&Node, RingError> {
+        if self.nodes.is_empty() {
+            return Err(RingError::EmptyRing);
+        }
+        self.nearest_node_under_load(self.digest(&key.to_be_bytes()), 1, LoadPolicy::Strict)
+    }
+
+    fn record_rejection(&mut self, key: String) {
+        self.rejections += 1;
+        self.metrics.on_reject(&key);
+        if self.diagnostics.contains(Diagnostics::REJECTION_LOG) {
+            self.rejection_log.push(key);
+        }
+    }
+
+    // counts a placed key's cost towards its node's load, remembering it if tracking is on
+    fn record_assignment(&mut self, node: Node, key: Option<String>, cost: u64) -> AssignOutcome {
+        let node_name = node.get_name();
+        let load = match self.load_per_node.get(node_name) {
+            None => 0,
+            Some(&val) => val,
+        };
+        let new_load = load.saturating_add(cost);
+        self.load_per_node.insert(node_name.to_string(), new_load);
+        self.total_load = self.total_load.saturating_add(cost);
+        if let (Some(assignments), Some(key)) = (self.assignments.as_mut(), key) {
+            assignments.insert(key, (node_name.to_string(), cost));
+        }
+        if self.diagnostics.contains(Diagnostics::TIMESTAMPS) {
+            self.last_assigned.insert(node_name.to_string(), Instant::now());
+        }
+        self.metrics.on_assign(&node, new_load);
+        self.outcome(node, new_load)
+    }
+
+    // the outcome of a tracked key's earlier assignment, leaving its load alone
+    fn already_assigned(&self, key: &str) -> Option<AssignOutcome> {
+        let (name, _cost) = self.assignments.as_ref()?.get(key)?;
+        let node = self.physical_node(name).cloned().unwrap_or_else(|| Node::new(name.clone()));
+        let load = self.load_per_node.get(name).copied().unwrap_or(0);
+        Some(self.outcome(node, load))
+    }
+
+    fn outcome(&self, node: Node, new_load: u64) -> AssignOutcome {
+        let max_allowed_load = self.max_allowed_load(node.get_name(), self.load_factor);
+        AssignOutcome {
+            above_average: new_load as f64 > self.average_load(),
+            near_cap: new_load >= max_allowed_load,
+            new_load,
+            node,
+        }
+    }
+
+    /// Rebuilds load accounting from the authoritative set of assigned keys, e.g. after a restart
+    /// that restored only the topology. Every load is zeroed and the keys are placed again in
+    /// order, exactly as `assign_key` would place them; keys no node can take are skipped. No
+    /// metrics hooks fire and `last_assigned` is left alone, since nothing was actually assigned.
+    pub fn recompute_load(&mut self, keys: &[String]) {
+        for load in self.load_per_node.values_mut() {
+            *load = 0;
+        }
+        self.total_load = 0;
+        if let Some(assignments) = self.assignments.as_mut() {
+            assignments.clear();
+        }
+        for key in keys {
+            let node_name = match self.try_get_node(key.clone()) {
+                Err(_) => continue,
+                Ok(node) => node.get_name().clone(),
+            };
+            self.total_load += 1;
+            if let Some(assignments) = self.assignments.as_mut() {
+                assignments.insert(key.clone(), (node_name.clone(), 1));
+            }
+            *self.load_per_node.entry(node_name).or_insert(0) += 1;
+        }
+    }
+
+    /// Each physical node's name and load, in no particular order, without copying the load map.
+    pub fn iter_loads(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.load_per_node.iter().map(|(name, &load)| (name.as_str(), load))
+    }
+
+    /// Every physical node's current load, for checkpointing separately from the topology.
+    pub fn export_load(&self) -> HashMap<String, u64> {
+        self.load_per_node.clone()
+    }
+
+    /// Restores loads saved by `export_load`, replacing the current ones. Nodes missing from
+    /// `load` are set to zero and `total_load` is recomputed. Fails, changing nothing, naming a
+    /// node in `load` that is not in the ring. Tracked key assignments are left as they are.
+    pub fn import_load(&mut self, load: HashMap<String, u64>) -> Result<(), ImportLoadError> {
+        let mut unknown: Vec<&String> = load.keys().filter(|name| !self.load_per_node.contains_key(*name)).collect();
+        unknown.sort();
+        if let Some(name) = unknown.first() {
+            return Err(ImportLoadError::UnknownNode(name.to_string()));
+        }
+        for (name, current) in self.load_per_node.iter_mut() {
+            *current = load.get(name).copied().unwrap_or(0);
+        }
+        self.total_load = self.load_per_node.values().fold(0u64, |sum, load| sum.saturating_add(*load));
+        Ok(())
+    }
+
+    /// Sets the node `assign_or_overflow` falls back to when no ring node can take a key,
+    /// typically a spill-over pool sized for the worst case. It gets no ring positions, so
+    /// `get_node` never returns it.
+    pub fn set_overflow_node(&mut self, node: Node) {
+        self.overflow_node = Some(node);
+    }
+
+    /// Number of keys `assign_or_overflow` has sent to the overflow node.
+    pub fn overflow_load(&self) -> u64 {
+        self.overflow_load
+    }
+
+    /// Assigns a key like `assign_key`, or sends it to the overflow node if the ring can't take
+    /// it. Overflowed keys count towards `overflow_load` only, leaving ring loads and caps as
+    /// they were, and are not tracked for `unassign_key`. A key only counts as rejected, and
+    /// comes back as the ring's error, when it has to overflow and no overflow node was set with
+    /// `set_overflow_node`.
+    pub fn assign_or_overflow(&mut self, key: String) -> Result<Node, RingError> {
+        if let Some(outcome) = self.already_assigned(&key) {
+            return Ok(outcome.node);
+        }
+        match self.try_get_node(key.clone()) {
+            Ok(node) => Ok(self.record_assignment(node, Some(key), 1).node),
+            Err(err) => match &self.overflow_node {
+                Some(node) => {
+                    self.overflow_load += 1;
+                    Ok(node.clone())
+                }
+                None => {
+                    self.record_rejection(key);
+                    Err(err)
+                }
+            },
+        }
+    }
+
+    /// When the node last received a key, or None if it never has, is unknown, or
+    /// `Diagnostics::TIMESTAMPS` is off. A node that is up but never picked usually has too few,
+    /// or too clustered, ring positions.
+    pub fn last_assigned(&self, name: &str) -> Option<Instant> {
+        self.last_assigned.get(name).copied()
+    }
+
+    /// Keys `assign_key` rejected since the last call, oldest first, or None if
+    /// `Diagnostics::REJECTION_LOG` is off. Taking the log empties it, which bounds its memory to
+    /// the rejections between two calls.
+    pub fn take_rejection_log(&mut self) -> Option<Vec<String>> {
+        if !self.diagnostics.contains(Diagnostics::REJECTION_LOG) {
+            return None;
+        }
+        Some(std::mem::take(&mut self.rejection_log))
+    }
+
+    /// Every `add_node`, `add_node_at_positions`, `remove_node` and `set_replicas` since the ring
+    /// was built, oldest first, or None if `Diagnostics::TOPOLOGY_LOG` is off. Changes made
+    /// through other methods show up as the calls they make, e.g. `rebalance_replicas` as the
+    /// `set_replicas` it settled on. Unlike the rejection log this one is never trimmed.
+    pub fn topology_log(&self) -> Option<&[TopologyEvent]> {
+        if !self.diagnostics.contains(Diagnostics::TOPOLOGY_LOG) {
+            return None;
+        }
+        Some(&self.topology_log)
+    }
+
+    /// A channel receiving every topology change from now on, as `topology_log` would record it,
+    /// so connection pools and routers can react to nodes coming and going without polling.
+    /// Sequence numbers match the log's when it is on. A dropped receiver is forgotten at the
+    /// next change, and a ring without subscribers pays nothing. Clones of the ring, such as the
+    /// ones `SnapshotRing::update` publishes, keep sending to the same subscribers.
+    pub fn subscribe(&mut self) -> Receiver<TopologyEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Applies logged topology changes in order, e.g. to rebuild a ring from another one's
+    /// `topology_log`. Replaying a whole log into a ring built with the same hasher, ring width
+    /// and placement version gives the same topology; loads are not part of the log.
+    pub fn replay_topology(&mut self, events: &[TopologyEvent]) {
+        for event in events {
+            self.apply_topology(&event.change);
+        }
+    }
+
+    /// The node `key` mapped to after the first `version` logged topology changes, i.e. as of the
+    /// change with sequence number `version - 1`; `version` 0 is the empty ring the log starts
+    /// from and the log's length is the ring as it stands. Answers questions like which node
+    /// held a key before the last rebalance. The ring is rebuilt by replaying the log, which
+    /// takes as long as building it did, and loads are not logged, so the answer ignores them
+    /// like `get_node_with_policy` with `LoadPolicy::Ignore`. Strict mode applies as it does to
+    /// `get_node`. None if `Diagnostics::TOPOLOGY_LOG` is off, `version` is past the end of the
+    /// log, the ring was empty then or strict mode refuses the key.
+    pub fn get_node_at_version(&self, key: String, version: u64) -> Option<Node> {
+        let events = self.topology_log()?.get(..usize::try_from(version).ok()?)?;
+        let mut past = ConsistentHash::builder()
+            .hasher(self.hasher)
+            .ring_width(self.ring_width)
+            .placement_version(self.placement_version)
+            .strict(self.strict)
+            .build();
+        past.hash_tag_extractor = self.hash_tag_extractor.clone();
+        past.replay_topology(events);
+        past.get_node_with_policy(key, LoadPolicy::Ignore)
+    }
+
+    /// Stages topology changes in `f` and applies them all once it returns, so no lookup ever
+    /// sees the ring halfway through a reconfiguration. Nothing is applied if `f` calls
+    /// `RingTxn::abort` or panics. Returns whether the changes were applied. Through a
+    /// `SnapshotRing`, use its `transaction` so readers switch from the old ring to the new one
+    /// in a single step.
+    pub fn transaction(&mut self, f: impl FnOnce(&mut RingTxn)) -> bool {
+        let mut txn = RingTxn { changes: Vec::new(), aborted: false };
+        f(&mut txn);
+        if txn.aborted {
+            return false;
+        }
+        for change in txn.changes.iter() {
+            self.apply_topology(change);
+        }
+        true
+    }
+
+    fn apply_topology(&mut self, change: &TopologyChange) {
+        match change {
+            TopologyChange::AddNode { node, replicas } => self.add_node(node, *replicas),
+            TopologyChange::AddNodeAtPositions { node, positions } => self.add_node_at_positions(node, positions),
+            TopologyChange::RemoveNode { name } => {
+                self.remove_node(name.clone());
+            }
+            TopologyChange::SetReplicas { name, replicas } => {
+                self.set_replicas(name, *replicas);
+            }
+        }
+    }
+
+    fn record_topology(&mut self, change: TopologyChange) {
+        let logged = self.diagnostics.contains(Diagnostics::TOPOLOGY_LOG);
+        if !logged && self.subscribers.is_empty() {
+            return;
+        }
+        let event = TopologyEvent { sequence: self.topology_sequence, change };
+        self.topology_sequence += 1;
+        // subscribers that hung up are dropped on the first change they miss
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if logged {
+            self.topology_log.push(event);
+        }
+    }
+
+    /// The ring's load and size in the Prometheus text exposition format, ready to serve from a
+    /// `/metrics` endpoint. Everything is a gauge except `consistenthash_rejections_total`, which
+    /// counts keys `assign_key` and its variants rejected since the ring was built.
+    /// `consistenthash_average_load` is averaged the way the bounded-load cap is, following the
+    /// ring's `AverageBasis`. Nodes are listed by name so the output is stable between scrapes.
+    pub fn prometheus_metrics(&self) -> String {
+        let mut names: Vec<&String> = self.load_per_node.keys().collect();
+        names.sort();
+        let average = if self.nodes.is_empty() { 0.0 } else { self.total_load as f64 / self.average_count() as f64 };
+
+        let mut out = String::new();
+        out.push_str("# HELP consistenthash_node_load Keys assigned to a physical node.\n");
+        out.push_str("# TYPE consistenthash_node_load gauge\n");
+        for name in names {
+            out.push_str(&format!("consistenthash_node_load{{node=\"{}\"}} {}\n", escape_label(name), self.load_per_node[name]));
+        }
+        let metrics: [(&str, &str, &str, String); 5] = [
+            ("total_load", "gauge", "Keys assigned across all nodes.", self.total_load.to_string()),
+            ("physical_nodes", "gauge", "Nodes in the ring.", self.replicas.len().to_string()),
+            ("virtual_nodes", "gauge", "Ring positions across all nodes.", self.size().to_string()),
+            ("average_load", "gauge", "Keys assigned per node, or per ring position under AverageBasis::Virtual.", average.to_string()),
+            ("rejections_total", "counter", "Keys rejected because no node had room.", self.rejections.to_string()),
+        ];
+        for (name, kind, help, value) in metrics {
+            out.push_str(&format!("# HELP consistenthash_{} {}\n", name, help));
+            out.push_str(&format!("# TYPE consistenthash_{} {}\n", name, kind));
+            out.push_str(&format!("consistenthash_{} {}\n", name, value));
+        }
+        out
+    }
+
+    /// The diagnostics this ring records.
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diagnostics
+    }
+
+    /// Reports ring activity to `metrics` from now on, replacing any previous hooks.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Remembers which node every assigned key went to, so `unassign_key` can release the
+    /// load from the right node. Costs memory per assigned key; keys assigned before this is
+    /// enabled are not tracked.
+    pub fn enable_key_tracking(&mut self) {
+        self.diagnostics |= Diagnostics::KEY_TRACKING;
+        if self.assignments.is_none() {
+            self.assignments = Some(HashMap::new());
+        }
+    }
+
+    /// Releases a key's load from the node it was assigned to. Returns false if key tracking is
+    /// off or the key is not currently assigned.
+    pub fn unassign_key(&mut self, key: String) -> bool {
+        let (node_name, cost) = match self.assignments.as_mut().and_then(|assignments| assignments.remove(&key)) {
+            None => return false,
+            Some(assigned) => assigned,
+        };
+        if let Some(load) = self.load_per_node.get_mut(&node_name) {
+            *load = load.saturating_sub(cost);
+            self.total_load = self.total_load.saturating_sub(cost);
+        }
+        true
+    }
+
+    /// Every tracked key grouped by the node `get_node` routes it to right now, each node's keys
+    /// in ring order (by their hash, clockwise from position 0): the work list for moving keys to
+    /// where lookups go. Under the bounded-load cap that can differ from where a key was
+    /// assigned, see `keys_by_assignment`; keys `get_node` finds no node for are left out. None
+    /// if key tracking is off.
+    pub fn keys_by_node(&self) -> Option<HashMap<String, Vec<String>>> {
+        let mut grouped: HashMap<String, Vec<String>> = HashMap::new();
+        for key in self.assignments.as_ref()?.keys() {
+            if let Some(node) = self.get_node(key.clone()) {
+                grouped.entry(node.get_name().clone()).or_default().push(key.clone());
+            }
+        }
+        Some(self.in_ring_order(grouped))
+    }
+
+    /// Every tracked key grouped by the node it was assigned to, in ring order like
+    /// `keys_by_node`: the work list for draining a node. Keys stay under their node until
+    /// unassigned, and disappear when it is removed, so this is where the keys' load sits
+    /// rather than where a fresh `get_node` might send them once loads shift. None if key
+    /// tracking is off.
+    pub fn keys_by_assignment(&self) -> Option<HashMap<String, Vec<String>>> {
+        let mut grouped: HashMap<String, Vec<String>> = HashMap::new();
+        for (key, (node_name, _cost)) in self.assignments.as_ref()? {
+            grouped.entry(node_name.clone()).or_default().push(key.clone());
+        }
+        Some(self.in_ring_order(grouped))
+    }
+
+    // sorts each node's keys by their hash, then by key among equal hashes
+    fn in_ring_order(&self, mut grouped: HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
+        for keys in grouped.values_mut() {
+            keys.sort_by_cached_key(|key| (self.key_hash(key), key.clone()));
+        }
+        grouped
+    }
+
+    /// Adds every node of `other` that this ring lacks, with its replica count and capacity
+    /// weight, e.g. to fold a standby pool into a cluster. Nodes present in both must have the
+    /// same replica count. Nothing is changed unless the whole merge succeeds, and loads stay as
+    /// they were on each ring: merged nodes start empty here.
+    pub fn merge(&mut self, other: &ConsistentHash) -> Result<(), MergeError> {
+        if self.hasher != other.hasher {
+            return Err(MergeError::HasherMismatch {
+                ours: self.hasher_id().to_string(),
+                theirs: other.hasher_id().to_string(),
+            });
+        }
+        if self.ring_width != other.ring_width {
+            return Err(MergeError::RingWidthMismatch);
+        }
+        if self.placement_version != other.placement_version {
+            return Err(MergeError::PlacementVersionMismatch);
+        }
+        let mut names: Vec<&String> = other.replicas.keys().collect();
+        names.sort();
+        for name in names.iter() {
+            if let Some(&ours) = self.replicas.get(*name) {
+                if ours != other.replicas[*name] {
+                    return Err(MergeError::ReplicaConflict { name: name.to_string(), ours, theirs: other.replicas[*name] });
+                }
+            }
+        }
+
+        for name in names {
+            if self.replicas.contains_key(name) {
+                continue;
+            }
+            let node = other.physical_nodes[name].clone();
+            match other.pinned_positions.get(name) {
+                None => self.add_node(&node, other.replicas[name]),
+                Some(positions) => {
+                    let positions: Vec<Vec<u8>> = positions.iter().map(|position| other.position_bytes(*position)).collect();
+                    self.add_node_at_positions(&node, &positions)
+                }
+            }
+            if let Some(&weight) = other.capacity_weights.get(name) {
+                self.capacity_weights.insert(name.clone(), weight);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a node and all its ring positions, returning it along with the load it carried.
+    /// Returns None if the node is not in the ring; `try_remove_node` says why.
+    pub fn remove_node(& mut self, name: String) -> Option<(Node, u64)> {
+        self.try_remove_node(&name).ok().map(|removed| (removed.node, removed.load))
+    }
+
+    /// Like `remove_node`, but reports what was removed and tells an empty ring apart from an
+    /// unknown name, so an orchestrator removing the wrong node finds out.
+    pub fn try_remove_node(&mut self, name: &str) -> Result<RemovedNode, RemoveError> {
+        if self.replicas.is_empty() {
+            return Err(RemoveError::EmptyRing);
+        }
+        let node_name = name.to_string();
+        let num_replicas = match self.replicas.get(&node_name) {
+            None => return Err(RemoveError::NotFound(node_name)),
+            Some(&val) => val
+        };
+        self.release_positions(&node_name, self.node_hashes(&node_name, num_replicas));
+        let load = self.load_per_node.get(&node_name).copied().unwrap_or(0);
+        if let Some(load) = self.load_per_node.remove(&node_name) {
+            self.total_load = self.total_load.saturating_sub(load);
+        }
+        if let Some(assignments) = self.assignments.as_mut() {
+            assignments.retain(|_key, (assigned, _cost)| *assigned != node_name);
+        }
+        if let Some(load) = self.decayed_load.remove(&node_name) {
+            self.decayed_total -= load;
+        }
+
+        self.capacity_weights.remove(name);
+        self.pinned_positions.remove(name);
+        self.node_states.remove(name);
+        self.last_assigned.remove(name);
+        self.replicas.remove(name);
+        let node = self.physical_nodes.remove(name).unwrap_or_else(|| Node::new(node_name.clone()));
+        self.metrics.on_remove_node(name);
+        self.record_topology(TopologyChange::RemoveNode { name: node_name });
+        Ok(RemovedNode { node, load, replicas: num_replicas })
+    }
+
+    /// Removes every node matching `pred`, e.g. all nodes of a failed zone, and returns them in
+    /// the ring order of their first position. Each is removed as by `remove_node`, so its load
+    /// and tracked keys go with it.
+    pub fn remove_nodes_where(&mut self, pred: impl Fn(&Node) -> bool) -> Vec<Node> {
+        let matching: Vec<Node> = self.list_nodes_deduped().unwrap_or_default()
+            .into_iter()
+            .filter(|node| pred(node))
+            .collect();
+        matching.into_iter()
+            .filter_map(|node| self.remove_node(node.get_name().clone()))
+            .map(|(node, _load)| node)
+            .collect()
+    }
+
+    /// Replaces a failed node with a spare that takes over exactly the positions it held, so
+    /// every key it owned moves to the spare and no other key moves at all. The spare's positions
+    /// are pinned, as with `add_node_at_positions`, since they no longer follow from its name.
+    /// It inherits the failed node's load and tracked keys, but not its capacity weight or state.
+    /// Returns false, changing nothing, if the failed node is unknown or the spare is already in
+    /// the ring.
+    pub fn takeover(&mut self, failed_name: &str, replacement: &Node) -> bool {
+        if !self.replicas.contains_key(failed_name) || self.replicas.contains_key(replacement.get_name()) {
+            return false;
+        }
+        let positions: Vec<Vec<u8>> = self.nodes.iter()
+            .filter(|(_hash, node)| node.get_name() == failed_name)
+            .map(|(hash, _node)| self.position_bytes(*hash))
+            .collect();
+        let tracked: Vec<(String, u64)> = self.assignments.iter()
+            .flat_map(|assignments| assignments.iter())
+            .filter(|(_key, (assigned, _cost))| assigned == failed_name)
+            .map(|(key, (_assigned, cost))| (key.clone(), *cost))
+            .collect();
+        let decayed = self.decayed_load.get(failed_name).copied();
+        let load = match self.try_remove_node(failed_name) {
+            Err(_) => return false,
+            Ok(removed) => removed.load,
+        };
+
+        let name = replacement.get_name().clone();
+        self.add_node_at_positions(replacement, &positions);
+        self.load_per_node.insert(name.clone(), load);
+        self.total_load = self.total_load.saturating_add(load);
+        if let Some(assignments) = self.assignments.as_mut() {
+            for (key, cost) in tracked {
+                assignments.insert(key, (name.clone(), cost));
+            }
+        }
+        if let Some(decayed) = decayed {
+            self.decayed_load.insert(name, decayed);
+            self.decayed_total += decayed;
+        }
+        true
+    }
+
+    /// Removes a node and reports where its keyspace went: one entry per run of consecutive ring
+    /// positions it owned, naming the remaining node that now owns that arc. Returns nothing if
+    /// the node is unknown or was the only node in the ring.
+    pub fn remove_node_with_migration(&mut self, name: &str) -> Vec<Migration> {
+        let num_replicas = match self.replicas.get(name) {
+            None => return Vec::new(),
+            Some(&val) => val,
+        };
+        let removed: Vec<u128> = self.node_hashes(name, num_replicas);
+        let positions: Vec<(u128, &Node)> = self.nodes.iter().map(|(hash, node)| (*hash, node)).collect();
+        let mut migrations: Vec<Migration> = Vec::new();
+        if let Some(anchor) = positions.iter().position(|(_, node)| node.get_name() != name) {
+            let mut run: Option<(u128, u128, Node)> = None;
+            for step in 1..=positions.len() {
+                let i = (anchor + step) % positions.len();
+                let (hash, node) = positions[i];
+                if node.get_name() == name && removed.contains(&hash) {
+                    let start = match run.take() {
+                        None => positions[(i + positions.len() - 1) % positions.len()].0,
+                        Some((start, _, _)) => start,
+                    };
+                    run = Some((start, hash, node.clone()));
+                } else if let Some((start, end, from)) = run.take() {
+                    migrations.push(Migration {
+                        start: self.position_bytes(start),
+                        end: self.position_bytes(end),
+                        from,
+                        to: node.clone(),
+                    });
+                }
+            }
+        }
+        self.remove_node(name.to_string());
+        migrations
+    }
+
+    /// Moves a node to `num_replicas` ring positions, keeping its current load. Returns false if
+    /// the node is not in the ring or was placed with `add_node_at_positions`.
+    pub fn set_replicas(&mut self, name: &str, num_replicas: u32) -> bool {
+        if !self.move_replicas(name, num_replicas) {
+            return false;
+        }
+        self.record_topology(TopologyChange::SetReplicas { name: name.to_string(), replicas: num_replicas });
+        true
+    }
+
+    /// Moves a node at most `step` replicas towards `target_replicas`, for migrations spread over
+    /// many small steps. Call it until the returned step says `done`. Returns None, changing
+    /// nothing, if the node is unknown or was placed with `add_node_at_positions`. A step of 0
+    /// is treated as 1.
+    pub fn reweight_gradually(&mut self, name: &str, target_replicas: u32, step: u32) -> Option<ReweightStep> {
+        let current = *self.replicas.get(name)?;
+        if self.pinned_positions.contains_key(name) {
+            return None;
+        }
+        let step = step.max(1);
+        let replicas = if target_replicas > current {
+            current.saturating_add(step).min(target_replicas)
+        } else {
+            current.saturating_sub(step).max(target_replicas)
+        };
+        let before = self.ownership_fractions()[name];
+        if replicas != current {
+            self.set_replicas(name, replicas);
+        }
+        // positions only come or go for this node, so every moved key moved to or from it
+        let moved_fraction = (self.ownership_fractions()[name] - before).abs();
+        Some(ReweightStep { replicas, remaining: replicas.abs_diff(target_replicas), moved_fraction })
+    }
+
+    // set_replicas without recording the change, for trial moves that get undone
+    fn move_replicas(&mut self, name: &str, num_replicas: u32) -> bool {
+        let old_replicas = match self.replicas.get(name) {
+            None => return false,
+            Some(&val) => val,
+        };
+        if self.pinned_positions.contains_key(name) {
+            return false;
+        }
+        let node = self.physical_nodes[name].clone();
+        self.release_positions(name, self.node_hashes(name, old_replicas));
+        self.place_positions(&node, self.node_hashes(name, num_replicas));
+        self.replicas.insert(name.to_string(), num_replicas);
+        true
+    }
+
+    /// Nudges replica counts, one position at a time, until each node's share of the keyspace is
+    /// as close as it gets to its share of the total replica count.
+    ///
+    /// After `remove_node` the freed arcs go to whichever nodes follow them clockwise, which can
+    /// leave one neighbour with far more than its fair share. The targets are taken from the
+    /// replica counts at the time of the call, and every change made is returned, ordered by node
+    /// name. Each step tries one replica more and one fewer on every node, scoring a try by the
+    /// single arc it hands over, so a 100 node ring of 160 replicas rebalances in well under a
+    /// second; `cargo bench` times it.
+    pub fn rebalance_replicas(&mut self) -> Vec<ReplicaAdjustment> {
+        let total_replicas: u32 = self.replicas.values().sum();
+        if total_replicas == 0 {
+            return Vec::new();
+        }
+        let original = self.replicas.clone();
+        let targets: HashMap<String, f64> = original.iter()
+            .map(|(name, &replicas)| (name.clone(), replicas as f64 / total_replicas as f64))
+            .collect();
+        let mut names: Vec<String> = original.keys()
+            .filter(|name| !self.pinned_positions.contains_key(*name))
+            .cloned()
+            .collect();
+        names.sort();
+
+        // each trial move adds or drops a single position, handing one arc between two nodes, so
+        // it is scored from that arc alone instead of by moving the node and re-measuring the ring
+        let mut fractions = self.ownership_fractions();
+        let mut error = balance_error(&fractions, &targets);
+        // each node's positions up to one replica beyond its current count
+        let mut positions: HashMap<String, Vec<u128>> = names.iter()
+            .map(|name| (name.clone(), self.replica_hashes(name, self.replicas[name] + 1)))
+            .collect();
+        for _ in 0..total_replicas {
+            let mut best: Option<(String, u32, f64, ArcHandover)> = None;
+            for name in names.iter() {
+                let replicas = self.replicas[name];
+                for candidate in [replicas.saturating_sub(1), replicas + 1] {
+                    if candidate == 0 || candidate == replicas {
+                        continue;
+                    }
+                    let handover = self.replica_handover(name, &positions[name], replicas, candidate);
+                    let candidate_error = error + handover.error_change(&fractions, &targets);
+                    if candidate_error < best.as_ref().map_or(error, |(_, _, best_error, _)| *best_error) {
+                        best = Some((name.clone(), candidate, candidate_error, handover));
+                    }
+                }
+            }
+            match best {
+                None => break,
+                Some((name, replicas, _, handover)) => {
+                    self.set_replicas(&name, replicas);
+                    positions.insert(name.clone(), self.replica_hashes(&name, replicas + 1));
+                    handover.apply(&mut fractions);
+                    error = balance_error(&fractions, &targets);
+                }
+            }
+        }
+
+        names.into_iter()
+            .filter(|name| self.replicas[name] != original[name])
+            .map(|name| ReplicaAdjustment {
+                old_replicas: original[&name],
+                new_replicas: self.replicas[&name],
+                name,
+            })
+            .collect()
+    }
+
+    // the arc that changes owner when a node goes from `replicas` to `candidate`, one more or one
+    // fewer, given at least that many of its replica positions in `hashes`. Replica positions
+    // don't depend on the count, so the move adds or drops exactly the position of replica
+    // `max(replicas, candidate) - 1`, resolved as `place_positions` and `release_positions` would.
+    fn replica_handover(&self, name: &str, hashes: &[u128], replicas: u32, candidate: u32) -> ArcHandover {
+        let kept = replicas.min(candidate);
+        let hash = hashes[kept as usize];
+        let none = ArcHandover { from: None, to: None, fraction: 0.0 };
+        // the node's other replicas already hash here, so it keeps or lacks the position either way
+        if hashes[..kept as usize].contains(&hash) {
+            return none;
+        }
+        let arc = match self.neighbours(hash) {
+            None => 1.0,
+            Some((previous, _next)) => hash.wrapping_sub(previous) as f64 / RING_SIZE,
+        };
+        let holder = self.nodes.get(&hash).map(|holder| holder.get_name().as_str());
+        if candidate > replicas {
+            return match holder {
+                Some(holder) if holder == name || name > holder => none,
+                Some(holder) => ArcHandover { from: Some(holder.to_string()), to: Some(name.to_string()), fraction: arc },
+                None => {
+                    let owner = self.neighbours(hash).map(|(_previous, next)| self.nodes[&next].get_name().clone());
+                    if owner.as_deref() == Some(name) {
+                        return none;
+                    }
+                    ArcHandover { from: owner, to: Some(name.to_string()), fraction: arc }
+                }
+            };
+        }
+        if holder != Some(name) {
+            return none;
+        }
+        let claimant = self.shared_positions.get(&hash)
+            .and_then(|claimants| claimants.iter().filter(|claimant| claimant.get_name() != name).min_by(|a, b| a.get_name().cmp(b.get_name())))
+            .map(|claimant| claimant.get_name().clone());
+        let to = claimant.or_else(|| self.neighbours(hash).map(|(_previous, next)| self.nodes[&next].get_name().clone()));
+        if to.as_deref() == Some(name) {
+            return none;
+        }
+        ArcHandover { from: Some(name.to_string()), to, fraction: arc }
+    }
+
+    // the positions either side of `hash`, wrapping round the ring and skipping `hash` itself.
+    // None if no other position exists.
+    fn neighbours(&self, hash: u128) -> Option<(u128, u128)> {
+        let previous = self.nodes.range(..hash).next_back()
+            .or_else(|| self.nodes.range(hash.checked_add(1)?..).next_back())?;
+        let next = hash.checked_add(1)
+            .and_then(|after| self.nodes.range(after..).next())
+            .or_else(|| self.nodes.range(..hash).next())?;
+        Some((*previous.0, *next.0))
+    }
+
+    /// Fraction of the keyspace owned by each physical node, ignoring load. A position owns the
+    /// arc running counter-clockwise back to the previous position.
+    pub fn ownership_fractions(&self) -> HashMap<String, f64> {
+        let mut fractions: HashMap<String, f64> = self.replicas.keys().map(|name| (name.clone(), 0.0)).collect();
+        let positions: Vec<(u128, &Node)> = self.nodes.iter().map(|(hash, node)| (*hash, node)).collect();
+        if positions.len() == 1 {
+            fractions.insert(positions[0].1.get_name().clone(), 1.0);
+            return fractions;
+        }
+        for (i, (position, node)) in positions.iter().enumerate() {
+            let previous = positions[(i + positions.len() - 1) % positions.len()].0;
+            let arc = position.wrapping_sub(previous);
+            *fractions.entry(node.get_name().clone()).or_insert(0.0) += arc as f64 / RING_SIZE;
+        }
+        fractions
+    }
+
+    /// The node that would take over the most keyspace if `failed_name` went down, with the
+    /// fraction of the whole keyspace it would gain, to check that no single failure piles onto
+    /// one neighbour. With few replicas one neighbour can inherit nearly everything the failed
+    /// node owned; more replicas spread it out. Computed on a copy of the ring with the node
+    /// removed, ignoring load; ties go to the smaller name. None if the node is unknown or no
+    /// other node has ring positions.
+    pub fn worst_case_absorber(&self, failed_name: &str) -> Option<(Node, f64)> {
+        if !self.replicas.contains_key(failed_name) {
+            return None;
+        }
+        let before = self.ownership_fractions();
+        let mut survivors = self.scratch_copy();
+        survivors.remove_node(failed_name.to_string());
+        if survivors.nodes.is_empty() {
+            return None;
+        }
+        let (name, gained) = survivors.ownership_fractions().into_iter()
+            .map(|(name, fraction)| {
+                let gained = fraction - before.get(&name).copied().unwrap_or(0.0);
+                (name, gained)
+            })
+            .max_by(|(a_name, a), (b_name, b)| {
+                a.partial_cmp(b).unwrap_or(Ordering::Equal).then_with(|| b_name.cmp(a_name))
+            })?;
+        let node = survivors.physical_node(&name).cloned().unwrap_or_else(|| Node::new(name));
+        Some((node, gained))
+    }
+
+    /// Keys each physical node would hold out of `total_keys` uniformly hashed ones, from its
+    /// ownership fraction and ignoring load, so nothing is assigned. Counts are rounded so they
+    /// add up to `total_keys` (unless the ring is empty), the largest remainders rounding up.
+    pub fn estimate_distribution(&self, total_keys: u64) -> HashMap<String, u64> {
+        let mut shares: Vec<(String, f64)> = self.ownership_fractions().into_iter()
+            .map(|(name, fraction)| (name, fraction * total_keys as f64))
+            .collect();
+        let mut estimate: HashMap<String, u64> = shares.iter()
+            .map(|(name, share)| (name.clone(), share.floor() as u64))
+            .collect();
+        if self.nodes.is_empty() {
+            return estimate;
+        }
+        let assigned: u64 = estimate.values().sum();
+        shares.sort_by(|(a_name, a), (b_name, b)| {
+            (b - b.floor()).total_cmp(&(a - a.floor())).then(a_name.cmp(b_name))
+        });
+        for (name, _share) in shares.iter().cycle().take(total_keys.saturating_sub(assigned) as usize) {
+            *estimate.get_mut(name).unwrap() += 1;
+        }
+        estimate
+    }
+
+    /// The physical node owning a key's position (the next one clockwise) and the closest
+    /// different physical node counter-clockwise of it, ignoring load. The counter-clockwise
+    /// side is None when the ring has a single physical node.
+    pub fn neighbors(&self, key: String) -> (Option<Node>, Option<Node>) {
+        let hash: u128 = self.key_hash(&key);
+        let successor = match self.clockwise(hash).next() {
+            None => return (None, None),
+            Some((_hash, node)) => node,
+        };
+        let predecessor = self.nodes.range(..hash)
+            .rev()
+            .chain(self.nodes.range(hash..).rev())
+            .map(|(_hash, node)| node)
+            .find(|node| *node != successor);
+        (Some(successor.clone()), predecessor.cloned())
+    }
+
+    /// Where a write for `key` goes: the physical node owning its position as primary, then the
+    /// next `replication_factor - 1` distinct physical nodes clockwise as replicas, ignoring
+    /// load. There are fewer replicas when the ring has fewer physical nodes. None when the ring
+    /// is empty or `replication_factor` is 0.
+    pub fn get_placement(&self, key: String, replication_factor: usize) -> Option<Placement> {
+        if replication_factor == 0 {
+            return None;
+        }
+        let hash: u128 = self.key_hash(&key);
+        let mut nodes: Vec<Node> = Vec::new();
+        for (_hash, node) in self.clockwise(hash) {
+            if nodes.len() == replication_factor {
+                break;
+            }
+            if !nodes.contains(node) {
+                nodes.push(node.clone());
+            }
+        }
+        if nodes.is_empty() {
+            return None;
+        }
+        let primary = nodes.remove(0);
+        Some(Placement { primary, replicas: nodes })
+    }
+
+    /// The node to try on retry `attempt` of a request for `key`: attempt 0 is the physical node
+    /// owning its position, attempt 1 the next distinct physical node clockwise and so on,
+    /// ignoring load. The same key and attempt always give the same node, so clients get a
+    /// reproducible retry order without tracking which nodes they already tried. Attempts past
+    /// the number of physical nodes start over from the owner. None on an empty ring.
+    pub fn get_node_attempt(&self, key: String, attempt: u32) -> Option<Node> {
+        let hash: u128 = self.key_hash(&key);
+        let mut tried: Vec<&Node> = Vec::new();
+        for (_hash, node) in self.clockwise(hash) {
+            if tried.contains(&node) {
+                continue;
+            }
+            if tried.len() == attempt as usize {
+                return Some(node.clone());
+            }
+            tried.push(node);
+        }
+        if tried.is_empty() {
+            return None;
+        }
+        Some(tried[attempt as usize % tried.len()].clone())
+    }
+
+    /// Spreads reads of a known hot key over the first `fanout` distinct nodes clockwise of it,
+    /// for the single-key hotspot bounded load can't help with: every request for one key hashes
+    /// to the same position. Writers must then keep the key on all of `hot_key_nodes`. A fanout
+    /// of 1 or less makes the key an ordinary one again.
+    pub fn register_hot_key(&mut self, key: String, fanout: usize) {
+        if fanout <= 1 {
+            self.hot_keys.remove(&key);
+        } else {
+            self.hot_keys.insert(key, fanout);
+        }
+    }
+
+    /// The nodes a hot key's copies live on, in the order `get_node_for_read` picks them, or just
+    /// the `get_node` owner for any other key. Fewer come back when the ring has fewer nodes.
+    pub fn hot_key_nodes(&self, key: String) -> Vec<Node> {
+        match self.hot_keys.get(&key) {
+            None => self.get_node(key).into_iter().collect(),
+            Some(&fanout) => match self.get_placement(key, fanout) {
+                None => Vec::new(),
+                Some(placement) => std::iter::once(placement.primary).chain(placement.replicas).collect(),
+            },
+        }
+    }
+
+    /// The node to read `key` from for request number `request`. Hot keys cycle through their
+    /// `hot_key_nodes` like `get_node_attempt`, so a given request number always reads from the
+    /// same copy and consecutive requests spread evenly; other keys go to `get_node`.
+    pub fn get_node_for_read(&self, key: String, request: u32) -> Option<Node> {
+        match self.hot_keys.get(&key) {
+            None => self.get_node(key),
+            Some(&fanout) => self.get_node_attempt(key, request % fanout as u32),
+        }
+    }
+
+    /// Up to `n` physical nodes for `key`, each in a different zone: the owner of its position,
+    /// then the next nodes clockwise whose zone isn't represented yet, ignoring load. Nodes
+    /// without a zone tag count as a zone of their own. Fewer than `n` come back when the ring
+    /// spans fewer zones.
+    pub fn get_n_nodes_distinct_zones(&self, key: String, n: usize) -> Vec<Node> {
+        let hash: u128 = self.key_hash(&key);
+        let mut nodes: Vec<Node> = Vec::new();
+        for (_hash, node) in self.clockwise(hash) {
+            if nodes.len() == n {
+                break;
+            }
+            let taken = nodes.iter().any(|chosen| {
+                chosen == node || node.zone().is_some_and(|zone| chosen.zone() == Some(zone))
+            });
+            if !taken {
+                nodes.push(node.clone());
+            }
+        }
+        nodes
+    }
+
+    /// Shard in `[0, num_shards)` for a key, from the same hash (hash tags included) the ring
+    /// routes it by. This is a pure hash-to-bucket mapping that knows nothing of the ring's
+    /// nodes: two keys on the same shard can live on different nodes. It uses jump consistent
+    /// hashing (Lamping and Veach), so growing from `n` to `n + 1` shards only moves about
+    /// `1 / (n + 1)` of the keys, all onto the new shard. 0 shards is treated as 1.
+    pub fn shard_id(&self, key: &str, num_shards: u32) -> u32 {
+        let mut state = (self.key_hash(key) >> 64) as u64;
+        let (mut shard, mut next): (i64, i64) = (-1, 0);
+        while next < num_shards.max(1) as i64 {
+            shard = next;
+            state = state.wrapping_mul(2862933555777941757).wrapping_add(1);
+            next = ((shard + 1) as f64 * ((1u64 << 31) as f64 / ((state >> 33) + 1) as f64)) as i64;
+        }
+        shard as u32
+    }
+
+    /// The node owning the point halfway clockwise from `key_a`'s position to `key_b`'s, ignoring
+    /// load, e.g. to pick a split point for a hot range. Equal positions give that position's
+    /// owner. None on an empty ring.
+    pub fn owner_of_midpoint(&self, key_a: &str, key_b: &str) -> Option<Node> {
+        let start = self.key_hash(key_a);
+        let end = self.key_hash(key_b);
+        let midpoint = start.wrapping_add(end.wrapping_sub(start) / 2);
+        self.clockwise(midpoint).next().map(|(_hash, node)| node.clone())
+    }
+
+    /// Physical nodes with a ring position in `[start, end)`, in ring order and without
+    /// duplicates. A `start` after `end` wraps around the top of the ring.
+    pub fn owner_of_range(&self, start: &[u8], end: &[u8]) -> Vec<Node> {
+        let mut owners: Vec<Node> = Vec::new();
+        let (start, end) = (position_value(start), position_value(end));
+        let in_range: Box<dyn Iterator<Item = (&u128, &Node)>> = match start.cmp(&end) {
+            Ordering::Less => Box::new(self.nodes.range(start..end)),
+            Ordering::Equal => return owners,
+            Ordering::Greater => Box::new(self.nodes.range(start..).chain(self.nodes.range(..end))),
+        };
+        for (_hash, node) in in_range {
+            if !owners.contains(node) {
+                owners.push(node.clone());
+            }
+        }
+        owners
+    }
+
+    /// Works out the operations that turn this ring into `target_nodes` (name and replica count
+    /// pairs), and how much of the keyspace each one moves, without touching the ring. New nodes
+    /// are added first, then replica counts change, then nodes go away, so capacity is added
+    /// before any is taken out. The fractions ignore load, measuring which node owns each arc.
+    pub fn plan_scale(&self, target_nodes: &[(String, u32)]) -> ScalePlan {
+        let target: HashMap<&String, u32> = target_nodes.iter().map(|(name, replicas)| (name, *replicas)).collect();
+        let mut adds: Vec<ScaleOp> = Vec::new();
+        let mut changes: Vec<ScaleOp> = Vec::new();
+        let mut removes: Vec<ScaleOp> = Vec::new();
+        for (name, &replicas) in target.iter() {
+            match self.replicas.get(*name) {
+                None => adds.push(ScaleOp::Add { name: (*name).clone(), replicas }),
+                Some(&current) if current != replicas => {
+                    changes.push(ScaleOp::SetReplicas { name: (*name).clone(), from: current, to: replicas });
+                }
+                Some(_) => {}
+            }
+        }
+        for name in self.replicas.keys() {
+            if !target.contains_key(name) {
+                removes.push(ScaleOp::Remove { name: name.clone() });
+            }
+        }
+        for ops in [&mut adds, &mut changes, &mut removes] {
+            ops.sort_by(|a, b| a.name().cmp(b.name()));
+        }
+
+        let mut ring = self.scratch_copy();
+        let mut steps: Vec<ScaleStep> = Vec::new();
+        for op in adds.into_iter().chain(changes).chain(removes) {
+            let before = ring.clone();
+            match &op {
+                ScaleOp::Add { name, replicas } => ring.add_node(&Node::new(name.clone()), *replicas),
+                ScaleOp::SetReplicas { name, to, .. } => {
+                    ring.set_replicas(name, *to);
+                }
+                ScaleOp::Remove { name } => {
+                    ring.remove_node(name.clone());
+                }
+            }
+            steps.push(ScaleStep { moved_fraction: before.moved_fraction(&ring), op });
+        }
+        ScalePlan { total_moved_fraction: self.moved_fraction(&ring), steps }
+    }
+
+    // a copy of the ring for what-if changes: no metrics hooks fire, subscribers hear nothing and
+    // nothing is logged, so callers never see changes that were not made to the ring itself
+    fn scratch_copy(&self) -> ConsistentHash {
+        let mut ring = self.clone();
+        ring.metrics = Arc::new(NoopMetrics);
+        ring.subscribers.clear();
+        ring.diagnostics = Diagnostics::NONE;
+        ring
+    }
+
+    // fraction of the keyspace whose owner, ignoring load, differs between two rings. Owners only
+    // change at positions of either ring, so each arc between consecutive positions of the
+    // combined set has a single owner on each side.
+    fn moved_fraction(&self, other: &ConsistentHash) -> f64 {
+        match (self.nodes.is_empty(), other.nodes.is_empty()) {
+            (true, true) => return 0.0,
+            (true, false) | (false, true) => return 1.0,
+            (false, false) => {}
+        }
+        let mut boundaries: Vec<&u128> = self.nodes.keys().chain(other.nodes.keys()).collect();
+        boundaries.sort();
+        boundaries.dedup();
+        let owner = |ring: &ConsistentHash, hash: &u128| {
+            ring.clockwise(*hash).next().map(|(_hash, node)| node.get_name().clone())
+        };
+
+        let mut moved = 0.0;
+        for (i, hash) in boundaries.iter().enumerate() {
+            if owner(self, hash) == owner(other, hash) {
+                continue;
+            }
+            let previous = boundaries[(i + boundaries.len() - 1) % boundaries.len()];
+            let arc = (*hash).wrapping_sub(*previous);
+            moved += if arc == 0 { RING_SIZE } else { arc as f64 };
+        }
+        moved / RING_SIZE
+    }
+
+    /// Each node's load divided by its fair share of the total load, where the fair share follows
+    /// its ownership fraction. 1.2 means the node carries 20% more than the keyspace it owns would
+    /// suggest. Nodes owning no keyspace, such as ones with no replicas, have no fair share to
+    /// compare against and are left out. Empty while no load has been assigned.
+    pub fn load_skew(&self) -> HashMap<String, f64> {
+        if self.total_load == 0 {
+            return HashMap::new();
+        }
+        let fractions = self.ownership_fractions();
+        self.load_per_node.iter()
+            .filter_map(|(name, &load)| {
+                let fraction = fractions.get(name).copied().filter(|&fraction| fraction > 0.0)?;
+                Some((name.clone(), load as f64 / (self.total_load as f64 * fraction)))
+            })
+            .collect()
+    }
+
+    /// Runs a key trace through a copy of the ring, assigning each key as `assign_key` would, and
+    /// reports how the keys spread. The copy starts from the ring's current loads and no metrics
+    /// hooks fire, so the ring itself is left untouched. Useful to check a ring against
+    /// production traffic before deploying it.
+    pub fn simulate(&self, keys: impl Iterator<Item = String>) -> SimulationReport {
+        let mut ring = self.scratch_copy();
+        ring.assignments = None;
+
+        let mut per_node: HashMap<String, u64> = self.replicas.keys().map(|name| (name.clone(), 0)).collect();
+        let mut rejected = 0;
+        for key in keys {
+            match ring.assign_key(key) {
+                Ok(outcome) => *per_node.entry(outcome.node.get_name().clone()).or_insert(0) += 1,
+                Err(_) => rejected += 1,
+            }
+        }
+
+        let (mut max_over_mean, mut coefficient_of_variation) = (0.0, 0.0);
+        let placed: u64 = per_node.values().sum();
+        if placed > 0 {
+            let mean = placed as f64 / per_node.len() as f64;
+            let max = per_node.values().copied().max().unwrap_or(0) as f64;
+            let variance = per_node.values()
+                .map(|&count| (count as f64 - mean) * (count as f64 - mean))
+                .sum::<f64>() / per_node.len() as f64;
+            max_over_mean = max / mean;
+            coefficient_of_variation = variance.sqrt() / mean;
+        }
+        SimulationReport { per_node, rejected, max_over_mean, coefficient_of_variation }
+    }
+
+    /// Replays `keys` like `simulate` under both the ring's load factor and `new_factor`, to tune
+    /// the factor against a real workload before calling `set_load_factor`. Both runs start from
+    /// the ring's current loads and the ring is left untouched.
+    pub fn simulate_load_factor(&self, new_factor: f64, keys: &[String]) -> LoadFactorComparison {
+        LoadFactorComparison {
+            current: self.replay_under(self.load_factor, keys),
+            proposed: self.replay_under(new_factor, keys),
+        }
+    }
+
+    // one run of `simulate_load_factor`
+    fn replay_under(&self, load_factor: f64, keys: &[String]) -> LoadFactorRun {
+        let mut ring = self.clone();
+        ring.load_factor = load_factor;
+        let report = ring.simulate(keys.iter().cloned());
+        let max_load = report.per_node.iter()
+            .map(|(name, placed)| self.load_per_node.get(name).copied().unwrap_or(0) + placed)
+            .max()
+            .unwrap_or(0);
+        LoadFactorRun { load_factor, rejected: report.rejected, max_load }
+    }
+
+    /// Variance of the physical nodes' keyspace shares, each scaled so a perfectly even share is
+    /// 1.0. Its square root is the coefficient of variation, so 0.01 means a typical node owns
+    /// about 10% more or less than its fair share. Use it to check a node/replica combination
+    /// before going live; tiny rings score badly. An empty ring reports 0.0.
+    pub fn distribution_variance(&self) -> f64 {
+        let fractions = self.ownership_fractions();
+        if fractions.is_empty() {
+            return 0.0;
+        }
+        let count = fractions.len() as f64;
+        fractions.values()
+            .map(|fraction| {
+                let diff = fraction * count - 1.0;
+                diff * diff
+            })
+            .sum::<f64>() / count
+    }
+
+    /// Replica count that gives `node_count` equally weighted nodes a coefficient of variation of
+    /// about `target_cv` in their keyspace shares, as measured by `distribution_variance`.
+    ///
+    /// With `r` randomly placed positions per node, a node's share of the ring follows a Beta
+    /// distribution whose squared CV is `(n - 1) / (n * r + 1)`, so the spread shrinks with
+    /// `1 / sqrt(r)`. This solves that for `r`. A single node needs one position; a target of
+    /// zero or less can't be met and returns `u32::MAX`.
+    pub fn suggest_replicas(node_count: usize, target_cv: f64) -> u32 {
+        if node_count <= 1 {
+            return 1;
+        }
+        if target_cv <= 0.0 || target_cv.is_nan() {
+            return u32::MAX;
+        }
+        let n = node_count as f64;
+        let replicas = ((n - 1.0) / (target_cv * target_cv) - 1.0) / n;
+        replicas.ceil().clamp(1.0, u32::MAX as f64) as u32
+    }
+
+    /// Sets how many distinct under-capacity nodes, walking clockwise from a key, are compared
+    /// when placing it. The least-loaded of them is chosen (power of K choices), so a window of 1
+    /// always picks the first node with room. A window of 0 is treated as 1.
+    pub fn set_choice_window(&mut self, k: usize) {
+        self.choice_window = k.max(1);
+    }
+
+    /// Changes the load factor for every lookup from now on; see `simulate_load_factor` to try one
+    /// out first. Keys already placed stay where they are, even on nodes now over their cap.
+    pub fn set_load_factor(&mut self, load_factor: f64) {
+        self.load_factor = load_factor;
+    }
+
+    /// Sets the smallest bounded-load cap a node ever gets, scaled by its capacity weight. The cap
+    /// follows the average load, which a cold ring barely has: an idle ring counts its average as
+    /// 1, but from the first key on the average is tiny. Averaged per ring position
+    /// (`AverageBasis::Virtual`), the default of 1 lets a fresh ring take exactly one key per node
+    /// before rejecting anything, and it only loosens up once enough keys have been placed. A
+    /// warmup cap sized for the expected startup burst lets every node take that many keys
+    /// before the average starts to matter.
+    pub fn set_warmup_cap(&mut self, keys: u64) {
+        self.warmup_cap = keys;
+    }
+
+    /// Caps how many ring positions a lookup visits looking for a node with room, failing with
+    /// `RingError::ScanLimitExceeded` once they run out. Without a limit a nearly saturated ring
+    /// can walk every position on each `get_node` and `assign_key`. A limit of 0 is treated as 1.
+    pub fn set_scan_limit(&mut self, steps: usize) {
+        self.scan_limit = Some(steps.max(1));
+    }
+
+    /// Checks the ring's internal bookkeeping agrees with itself, returning every problem found.
+    ///
+    /// Verified: each registered node has a load entry and vice versa, `total_load` is the sum of
+    /// the per-node loads, every position a node should hold is on the ring, each node holds as
+    /// many positions as its replica count allows once hash collisions with other nodes are taken
+    /// into account, and no position belongs to a node that should not be there.
+    pub fn audit(&self) -> Result<(), Vec<AuditError>> {
+        let mut errors: Vec<AuditError> = Vec::new();
+
+        let mut names: Vec<&String> = self.replicas.keys().collect();
+        names.sort();
+        for name in names.iter() {
+            if !self.load_per_node.contains_key(*name) {
+                errors.push(AuditError::MissingLoad((*name).clone()));
+            }
+        }
+        let mut loaded: Vec<&String> = self.load_per_node.keys().collect();
+        loaded.sort();
+        for name in loaded {
+            if !self.replicas.contains_key(name) {
+                errors.push(AuditError::OrphanLoad(name.clone()));
+            }
+        }
+        match self.load_per_node.values().try_fold(0u64, |sum, load| sum.checked_add(*load)) {
+            None => errors.push(AuditError::LoadOverflow),
+            Some(load_sum) if load_sum != self.total_load => {
+                errors.push(AuditError::TotalLoadMismatch { total_load: self.total_load, load_sum });
+            }
+            Some(_) => {}
+        }
+
+        let mut expected: HashMap<&String, Vec<u128>> = HashMap::new();
+        for name in names.iter() {
+            let mut hashes = self.node_hashes(name, self.replicas[*name]);
+            hashes.sort();
+            hashes.dedup();
+            expected.insert(*name, hashes);
+        }
+        for name in names.iter() {
+            let mut allowed = 0;
+            for hash in expected[*name].iter() {
+                match self.nodes.get(hash) {
+                    None => errors.push(AuditError::MissingPosition { node: (*name).clone(), position: self.position_bytes(*hash) }),
+                    Some(owner) if owner.get_name() == *name => allowed += 1,
+                    // another node hashing to the same position may legitimately hold it
+                    Some(_) => {}
+                }
+            }
+            let actual = self.nodes.values().filter(|node| node.get_name() == *name).count();
+            if actual != allowed {
+                errors.push(AuditError::PositionCountMismatch { node: (*name).clone(), expected: allowed, actual });
+            }
+        }
+        for (hash, node) in self.nodes.iter() {
+            let claimed = expected.get(node.get_name()).is_some_and(|hashes| hashes.contains(hash));
+            if !claimed {
+                errors.push(AuditError::OrphanPosition { node: node.get_name().clone(), position: self.position_bytes(*hash) });
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(errors)
+    }
+
+    /// Whether two rings place every key on the same node, ignoring load: same hasher, ring
+    /// width, nodes, replica counts and ring positions.
+    pub fn same_topology(&self, other: &ConsistentHash) -> bool {
+        self.hasher == other.hasher
+            && self.ring_width == other.ring_width
+            && self.replicas == other.replicas
+            && self.nodes == other.nodes
+    }
+
+    /// The keys among `keys` whose owner differs between `before` and `after`, in their original
+    /// order, with the old and the new owner: exactly what a cache must invalidate after a
+    /// topology change. Owners are found ignoring load, as `get_node_with_policy` with
+    /// `LoadPolicy::Ignore` does. Keys neither ring can place, or only one of them can, are left
+    /// out since there is no owner to compare.
+    pub fn ownership_changes(
+        before: &ConsistentHash,
+        after: &ConsistentHash,
+        keys: &[String],
+    ) -> Vec<(String, Node, Node)> {
+        keys.iter()
+            .filter_map(|key| {
+                let old = before.get_node_with_policy(key.clone(), LoadPolicy::Ignore)?;
+                let new = after.get_node_with_policy(key.clone(), LoadPolicy::Ignore)?;
+                (old != new).then(|| (key.clone(), old, new))
+            })
+            .collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Rough number of bytes held by the ring positions, physical nodes, replica counts and loads:
+    /// the size of every key and value plus the strings they own. Each position stores its own
+    /// copy of the node, name and tags included, so this grows with nodes times replicas; 10000
+    /// replicas of a node named in 20 bytes come to about 840KB on 64-bit targets. Map overhead
+    /// and the ring's other bookkeeping are left out, so the real figure is somewhat higher.
+    pub fn memory_footprint(&self) -> usize {
+        let string_bytes = |value: &String| mem::size_of::<String>() + value.capacity();
+        let node_bytes = |node: &Node| {
+            mem::size_of::<Node>() + node.get_name().capacity()
+                + node.tags().iter().map(|(key, value)| string_bytes(key) + string_bytes(value)).sum::<usize>()
+        };
+        let positions: usize = self.nodes.values().map(|node| mem::size_of::<u128>() + node_bytes(node)).sum();
+        let physical: usize = self.physical_nodes.iter().map(|(name, node)| string_bytes(name) + node_bytes(node)).sum();
+        let replicas: usize = self.replicas.keys().map(|name| string_bytes(name) + mem::size_of::<u32>()).sum();
+        let loads: usize = self.load_per_node.keys().map(|name| string_bytes(name) + mem::size_of::<u64>()).sum();
+        positions + physical + replicas + loads
+    }
+
+    /// Ring positions a node holds right now, 0 for an unknown node. This falls short of its
+    /// replica count when some of its positions went to other nodes in hash collisions, the
+    /// divergence `verify_placement` predicts before adding a node.
+    pub fn virtual_count(&self, name: &str) -> usize {
+        self.nodes.values().filter(|node| node.get_name() == name).count()
+    }
+
+    /// Every ring position's node in ring order, so a node appears once per replica. Use
+    /// `list_nodes_deduped` for an inventory of physical nodes.
+    pub fn list_nodes(&self) -> Option<Vec<Node>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        Some(self.nodes.values().cloned().collect::<Vec<_>>())
+    }
+
+    /// Every ring position with the node holding it, in ascending position order, for callers
+    /// doing their own lookups. The clockwise owner of a hash `h` is the entry at
+    /// `positions.partition_point(|(position, _)| *position < h)`, wrapping to the first entry
+    /// past the end. The view ignores load entirely.
+    pub fn sorted_positions(&self) -> Vec<(Vec<u8>, &Node)> {
+        self.nodes.iter().map(|(hash, node)| (self.position_bytes(*hash), node)).collect()
+    }
+
+    /// The lowest ring position and its node, where keys hashing past `last_position` wrap to.
+    pub fn first_position(&self) -> Option<(Vec<u8>, &Node)> {
+        self.nodes.iter().next().map(|(hash, node)| (self.position_bytes(*hash), node))
+    }
+
+    /// The highest ring position and its node. It owns the arc from the position before it, and
+    /// keys hashing above it belong to `first_position`.
+    pub fn last_position(&self) -> Option<(Vec<u8>, &Node)> {
+        self.nodes.iter().next_back().map(|(hash, node)| (self.position_bytes(*hash), node))
+    }
+
+    /// Each physical node once, in the ring order of its first position.
+    pub fn list_nodes_deduped(&self) -> Option<Vec<Node>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut nodes: Vec<Node> = Vec::new();
+        for node in self.nodes.values() {
+            if !nodes.contains(node) {
+                nodes.push(node.clone());
+            }
+        }
+        Some(nodes)
+    }
+
+    /// Sizes of the arcs between consecutive ring positions, sorted ascending.
+    ///
+    /// Positions are read as big-endian u128 values, narrower rings being scaled up to 128 bits,
+    /// and the arc from the last position back around to the first is included. A ring with a
+    /// single position owns the whole keyspace, which does not fit in a u128, so it is reported
+    /// as `u128::MAX`.
+    pub fn position_gaps(&self) -> Vec<u128> {
+        let mut gaps: Vec<u128> = self.arcs().into_iter().map(|(_start, _end, size, _node)| size).collect();
+        gaps.sort_unstable();
+        gaps
+    }
+
+    /// Arcs wider than `min_gap`, in ring order, as the previous position (exclusive), the owning
+    /// position (inclusive) and the node owning it. These are the positions to look at when a
+    /// node owns more of the keyspace than it should. Sizes are measured like `position_gaps`.
+    pub fn coverage_gaps(&self, min_gap: u128) -> Vec<(Vec<u8>, Vec<u8>, Node)> {
+        self.arcs().into_iter()
+            .filter(|(_start, _end, size, _node)| *size > min_gap)
+            .map(|(start, end, _size, node)| (self.position_bytes(start), self.position_bytes(end), node.clone()))
+            .collect()
+    }
+
+    /// How unevenly a node's own positions are spread around the ring: the coefficient of
+    /// variation (standard deviation over mean) of the gaps from each of its positions to the
+    /// next one it holds, wrapping around. Evenly spaced positions score 0 and randomly hashed
+    /// ones about 1; well above 1 means the node's ownership is bunched into a few arcs, so a
+    /// handful of hot keys can land on it together. Comparing a lopsided node's score to the
+    /// others' tells whether moving its positions (`add_node_at_positions`) would help. Nodes with
+    /// fewer than two positions, or not in the ring, score 0.
+    pub fn local_density(&self, name: &str) -> f64 {
+        let positions: Vec<u128> = self.nodes.iter()
+            .filter(|(_hash, node)| node.get_name() == name)
+            .map(|(hash, _node)| *hash)
+            .collect();
+        if positions.len() < 2 {
+            return 0.0;
+        }
+        let gaps: Vec<f64> = positions.iter().enumerate()
+            .map(|(i, hash)| positions[(i + 1) % positions.len()].wrapping_sub(*hash) as f64 / RING_SIZE)
+            .collect();
+        let mean = 1.0 / gaps.len() as f64;
+        let variance = gaps.iter().map(|gap| (gap - mean).powi(2)).sum::<f64>() / gaps.len() as f64;
+        variance.sqrt() / mean
+    }
+
+    // every arc in ring order: the previous position, the owning position, the arc size and the
+    // owning node
+    fn arcs(&self) -> Vec<(u128, u128, u128, &Node)> {
+        let positions: Vec<(u128, &Node)> = self.nodes.iter().map(|(hash, node)| (*hash, node)).collect();
+        if positions.len() == 1 {
+            return vec![(positions[0].0, positions[0].0, u128::MAX, positions[0].1)];
+        }
+        let mut arcs = Vec::with_capacity(positions.len());
+        for (i, (hash, node)) in positions.iter().enumerate() {
+            let previous = positions[(i + positions.len() - 1) % positions.len()].0;
+            arcs.push((previous, *hash, hash.wrapping_sub(previous), *node));
+        }
+        arcs
+    }
+}
+
+/// A replica count change made by `rebalance_replicas`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplicaAdjustment {
+    pub name: String,
+    pub old_replicas: u32,
+    pub new_replicas: u32,
+}
+
+/// What `verify_placement` found about a node's would-be positions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PlacementReport {
+    /// Replicas asked for, one position each.
+    pub replicas: u32,
+    /// Distinct positions among them.
+    pub distinct: usize,
+    /// Positions the node would actually hold, after losing shared ones to nodes with smaller
+    /// names.
+    pub held: usize,
+    /// Positions another node already holds, with that node, whichever side would keep them.
+    pub collisions: Vec<(Vec<u8>, Node)>,
+}
+
+impl PlacementReport {
+    /// Whether the node would get one position per replica.
+    pub fn is_complete(&self) -> bool {
+        self.held == self.replicas as usize
+    }
+}
+
+/// One step of `reweight_gradually`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReweightStep {
+    /// The node's replica count after this step.
+    pub replicas: u32,
+    /// Replicas still to add or remove before reaching the target.
+    pub remaining: u32,
+    /// Fraction of the keyspace that changed owner in this step.
+    pub moved_fraction: f64,
+}
+
+impl ReweightStep {
+    pub fn done(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Most replicas `add_node_for_fraction` gives a node.
+pub const MAX_FRACTION_REPLICAS: u32 = 1 << 16;
+
+// share of the keyspace `mine` would own on a ring already holding `others`, ignoring names:
+// positions also in `others` stay with their current holder
+fn owned_fraction(others: &[u128], mine: &[u128]) -> f64 {
+    let mut ring: BTreeMap<u128, bool> = others.iter().map(|hash| (*hash, false)).collect();
+    for hash in mine {
+        ring.entry(*hash).or_insert(true);
+    }
+    if ring.len() == 1 {
+        return if ring.values().all(|mine| *mine) { 1.0 } else { 0.0 };
+    }
+    let mut previous = match ring.keys().next_back() {
+        None => return 0.0,
+        Some(last) => *last,
+    };
+    let mut owned = 0.0;
+    for (hash, mine) in ring.iter() {
+        if *mine {
+            owned += hash.wrapping_sub(previous) as f64 / RING_SIZE;
+        }
+        previous = *hash;
+    }
+    owned
+}
+
+// escapes a Prometheus label value: backslashes, double quotes and newlines
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// separates a node name from its replica number in virtual node identifiers
+const REPLICA_SEPARATOR: u8 = 0xFF;
+
+type HashTagExtractor = dyn Fn(&str) -> &str + Send + Sync;
+
+/// Redis style hash tag: the text between the first `{` and the first `}` after it, so
+/// `user:{42}:profile` and `user:{42}:settings` both route by `42`. Keys without a tag, or with
+/// an empty one like `{}`, are returned whole.
+pub fn hash_tag(key: &str) -> &str {
+    if let Some(open) = key.find('{') {
+        if let Some(len) = key[open + 1..].find('}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+/// Steps `plan_scale` would take to reach a target topology.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScalePlan {
+    pub steps: Vec<ScaleStep>,
+    /// Share of the keyspace owned by a different node at the end than at the start. Less than
+    /// the sum of the steps when later steps move arcs back.
+    pub total_moved_fraction: f64,
+}
+
+/// One operation of a `ScalePlan` and the share of the keyspace it moves to a new owner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScaleStep {
+    pub op: ScaleOp,
+    pub moved_fraction: f64,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScaleOp {
+    Add { name: String, replicas: u32 },
+    SetReplicas { name: String, from: u32, to: u32 },
+    Remove { name: String },
+}
+
+impl ScaleOp {
+    pub fn name(&self) -> &String {
+        match self {
+            ScaleOp::Add { name, .. } | ScaleOp::SetReplicas { name, .. } | ScaleOp::Remove { name } => name,
+        }
+    }
+}
+
+/// An inconsistency found by `ConsistentHash::audit`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AuditError {
+    /// A registered node has no load entry.
+    MissingLoad(String),
+    /// A load entry exists for a node that is not registered.
+    OrphanLoad(String),
+    /// `total_load` differs from the sum of the per-node loads.
+    TotalLoadMismatch { total_load: u64, load_sum: u64 },
+    /// The per-node loads add up to more than a u64 holds, so `total_load` has saturated.
+    LoadOverflow,
+    /// A position the node should hold is not on the ring.
+    MissingPosition { node: String, position: Vec<u8> },
+    /// The node holds a different number of positions than its replica count allows.
+    PositionCountMismatch { node: String, expected: usize, actual: usize },
+    /// A ring position belongs to a node that should not hold it.
+    OrphanPosition { node: String, position: Vec<u8> },
+}
+
+/// Where `assign_key` placed a key, with enough about the node's load afterwards to shed load
+/// before the ring saturates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssignOutcome {
+    pub node: Node,
+    /// The node's load including this key.
+    pub new_load: u64,
+    /// The node now carries more than the average load, the same average the max allowed load is
+    /// derived from.
+    pub above_average: bool,
+    /// The node has reached its max allowed load, so the next key hashing to it will spill over
+    /// to another node.
+    pub near_cap: bool,
+}
+
+/// One entry of `topology_log`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TopologyEvent {
+    /// Position of the change in the log, counting from 0.
+    pub sequence: u64,
+    pub change: TopologyChange,
+}
+
+/// A change to which nodes are on the ring and where, as recorded in `topology_log`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TopologyChange {
+    AddNode { node: Node, replicas: u32 },
+    /// Positions are in ring width bytes.
+    AddNodeAtPositions { node: Node, positions: Vec<Vec<u8>> },
+    RemoveNode { name: String },
+    SetReplicas { name: String, replicas: u32 },
+}
+
+/// Topology changes staged by `ConsistentHash::transaction`, applied in the order they were
+/// made. Each behaves like the ring method of the same name once applied.
+#[derive(Clone, Debug, Default)]
+pub struct RingTxn {
+    changes: Vec<TopologyChange>,
+    aborted: bool,
+}
+
+impl RingTxn {
+    pub fn add_node(&mut self, node: &Node, num_replicas: u32) {
+        self.changes.push(TopologyChange::AddNode { node: node.clone(), replicas: num_replicas });
+    }
+
+    pub fn add_node_at_positions(&mut self, node: &Node, positions: &[Vec<u8>]) {
+        self.changes.push(TopologyChange::AddNodeAtPositions { node: node.clone(), positions: positions.to_vec() });
+    }
+
+    pub fn remove_node(&mut self, name: &str) {
+        self.changes.push(TopologyChange::RemoveNode { name: name.to_string() });
+    }
+
+    pub fn set_replicas(&mut self, name: &str, num_replicas: u32) {
+        self.changes.push(TopologyChange::SetReplicas { name: name.to_string(), replicas: num_replicas });
+    }
+
+    /// Drops every staged change, so the transaction leaves the ring as it was.
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    /// Changes staged so far.
+    pub fn changes(&self) -> &[TopologyChange] {
+        &self.changes
+    }
+}
+
+/// A node taken off the ring by `try_remove_node`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemovedNode {
+    pub node: Node,
+    /// Load the node carried when it was removed.
+    pub load: u64,
+    /// Replica count it was placed with.
+    pub replicas: u32,
+}
+
+/// How a key trace spread over the ring, from `simulate`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationReport {
+    /// Keys from the trace placed on each physical node, including nodes that got none.
+    pub per_node: HashMap<String, u64>,
+    /// Keys no node could take.
+    pub rejected: u64,
+    /// The busiest node's count over the mean count; 1.0 is a perfect spread.
+    pub max_over_mean: f64,
+    /// Standard deviation of the per-node counts over their mean.
+    pub coefficient_of_variation: f64,
+}
+
+/// The same key trace replayed under two load factors, from `simulate_load_factor`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadFactorComparison {
+    pub current: LoadFactorRun,
+    pub proposed: LoadFactorRun,
+}
+
+/// How a key trace fared under one load factor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadFactorRun {
+    pub load_factor: f64,
+    /// Keys no node could take.
+    pub rejected: u64,
+    /// The busiest node's load once the trace was placed, including load it already had.
+    pub max_load: u64,
+}
+
+/// The nodes a replicated write goes to, from `get_placement`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Placement {
+    pub primary: Node,
+    /// Distinct from the primary and from each other, in ring order.
+    pub replicas: Vec<Node>,
+}
+
+/// An arc of the ring that changed owner. The arc runs clockwise from `start` (exclusive) to
+/// `end` (inclusive), matching how keys are placed on the first position at or after their hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Migration {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub from: Node,
+    pub to: Node,
+}
+
+// number of distinct ring positions, 2^128, as a float for computing keyspace fractions
+const RING_SIZE: f64 = 340282366920938463463374607431768211456.0;
+
+// the part of a key it is routed by: its hash tag if it has a non-empty one
+fn routed_key<'a>(extractor: Option<&HashTagExtractor>, key: &'a str) -> &'a str {
+    match extractor {
+        None => key,
+        Some(extractor) => {
+            let tag = extractor(key);
+            if tag.is_empty() { key } else { tag }
+        }
+    }
+}
+
+// the ring position of `data`: its digest cut to the ring width
+fn ring_position(hasher: HashAlgorithm, ring_width: RingWidth, data: &[u8]) -> u128 {
+    let mut hash = hasher.digest(data);
+    hash.truncate(ring_width.bytes());
+    position_value(&hash)
+}
+
+// sum of squared differences between each node's ownership fraction and its target
+fn balance_error(fractions: &HashMap<String, f64>, targets: &HashMap<String, f64>) -> f64 {
+    targets.iter()
+        .map(|(name, target)| {
+            let diff = fractions.get(name).copied().unwrap_or(0.0) - target;
+            diff * diff
+        })
+        .sum()
+}
+
+// a fraction of the keyspace changing owner in one replica move of `rebalance_replicas`. `from`
+// is None when the ring was empty, `to` when it ends up empty.
+struct ArcHandover {
+    from: Option<String>,
+    to: Option<String>,
+    fraction: f64,
+}
+
+impl ArcHandover {
+    // what the handover adds to `balance_error` over `fractions`
+    fn error_change(&self, fractions: &HashMap<String, f64>, targets: &HashMap<String, f64>) -> f64 {
+        let mut change = 0.0;
+        for (name, delta) in [(&self.from, -self.fraction), (&self.to, self.fraction)] {
+            let name = match name {
+                None => continue,
+                Some(name) => name,
+            };
+            if let Some(target) = targets.get(name) {
+                let diff = fractions.get(name).copied().unwrap_or(0.0) - target;
+                change += (diff + delta) * (diff + delta) - diff * diff;
+            }
+        }
+        change
+    }
+
+    fn apply(&self, fractions: &mut HashMap<String, f64>) {
+        if let Some(from) = &self.from {
+            *fractions.entry(from.clone()).or_insert(0.0) -= self.fraction;
+        }
+        if let Some(to) = &self.to {
+            *fractions.entry(to.clone()).or_insert(0.0) += self.fraction;
+        }
+    }
+}
+
+// interprets a ring hash as a big-endian u128, zero-padding hashes shorter than 16 bytes
+fn position_value(hash: &[u8]) -> u128 {
+    let mut bytes = [0u8; 16];
+    let len = hash.len().min(16);
+    bytes[..len].copy_from_slice(&hash[..len]);
+    u128::from_be_bytes(bytes)
+}
+
+/// Combines several fields into one routing key without the ambiguity of plain concatenation,
+/// where `"a" + "bc"` and `"ab" + "c"` give the same key. Each field is written as its length in
+/// bytes, a `:`, and the field itself, so `tenant` and `user` build `6:tenant4:user`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyBuilder {
+    key: String,
+}
+
+impl KeyBuilder {
+    pub fn new() -> KeyBuilder {
+        KeyBuilder { key: String::new() }
+    }
+
+    pub fn field(mut self, field: &str) -> KeyBuilder {
+        self.key.push_str(&field.len().to_string());
+        self.key.push(':');
+        self.key.push_str(field);
+        self
+    }
+
+    pub fn build(self) -> String {
+        self.key
+    }
+}
+
+/// Health of a node as far as routing is concerned, set with `set_node_state`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NodeState {
+    #[default]
+    Healthy,
+    /// Only gets keys when no healthy node clockwise of them has room.
+    Degraded,
+    /// Gets no keys at all, as if it had no ring positions.
+    Unhealthy,
+}
+
+/// What a lookup does when no node passes the bounded-load check.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SaturationPolicy {
+    /// Fail with `RingError::Saturated`.
+    #[default]
+    Reject,
+    /// Return the least-loaded healthy or degraded node anyway. This deliberately breaks the
+    /// bounded-load guarantee, letting nodes go over their cap, to keep serving through a
+    /// transient overload. Keys stop following the hash while it lasts, so caches see more
+    /// misses. A scan limit running out still fails with `RingError::ScanLimitExceeded`.
+    LeastLoaded,
+}
+
+/// What the average load behind the bounded-load cap is taken over.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AverageBasis {
+    /// Keys per node holding ring positions, as in the bounded-load paper: with load factor `c`
+    /// no node carries more than `c` times its fair share.
+    #[default]
+    Physical,
+    /// Keys per ring position. Caps come out roughly `replicas` times tighter, so rings saturate
+    /// long before nodes are anywhere near their fair share; this was the only behavior before
+    /// `AverageBasis` existed.
+    Virtual,
+}
+
+/// How strictly a single lookup applies the bounded-load cap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadPolicy {
+    /// Use the ring's load factor.
+    Strict,
+    /// Use this load factor instead of the ring's.
+    Relaxed(f64),
+    /// Skip the load check and take the first node clockwise.
+    Ignore,
+}
+
+/// Maps keys to nodes, implemented by both `ConsistentHash` and `AnchorHash` so callers can
+/// switch algorithms without changing their routing code.
+pub trait Router {
+    fn route(&self, key: String) -> Option<Node>;
+}
+
+impl Router for ConsistentHash {
+    fn route(&self, key: String) -> Option<Node> {
+        self.get_node(key)
+    }
+}
+
+/// Turns a routed `Node` into whatever callers actually talk to, like a pooled connection, for
+/// `get_node_async`. Resolution may have to wait on the network, so it returns a future and works
+/// with any async runtime.
+pub trait NodeResolver {
+    type Resolved;
+    type Future: Future<Output = Option<Self::Resolved>>;
+
+    fn resolve(&self, node: Node) -> Self::Future;
+}
+
+/// Hooks called as the ring changes, e.g. to feed Prometheus counters. Every method defaults to
+/// doing nothing, so implementations only override what they record.
+pub trait Metrics: Send + Sync {
+    /// `assign_key` placed a key on `node`, which now carries `new_load`.
+    fn on_assign(&self, _node: &Node, _new_load: u64) {}
+    /// `assign_key` found no node under its max allowed load for `key`.
+    fn on_reject(&self, _key: &str) {}
+    /// A node was added, or re-added with a new replica count.
+    fn on_add_node(&self, _name: &str) {}
+    /// A node was removed.
+    fn on_remove_node(&self, _name: &str) {}
+}
+
+/// The default `Metrics`, recording nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Number of bits in a ring position.
+///
+/// Narrower rings keep smaller BTreeMap keys but make it likelier that two positions collide.
+/// The node with the smaller name then holds the position, whatever order the nodes were added
+/// in, and the other holds one fewer until the winner leaves and hands it back;
+/// `verify_placement` shows which positions a node would lose. With `n` positions in total
+/// (nodes times replicas) the chance of any collision is roughly `n^2 / 2^(bits + 1)`:
+///
+/// | positions | 32-bit      | 64-bit        | 128-bit       |
+/// |-----------|-------------|---------------|---------------|
+/// | 1,000     | 1 in 8,600  | 1 in 3.7e13   | 1 in 6.8e32   |
+/// | 100,000   | ~1 expected | 1 in 3.7e9    | 1 in 6.8e28   |
+///
+/// 32 bits is fine for a handful of nodes with modest replica counts. Like the hasher, the width
+/// decides placement and must match across every ring built for a cluster.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RingWidth {
+    Bits32,
+    Bits64,
+    #[default]
+    Bits128,
+}
+
+/// How a node's replica positions are derived from its name. Each replica is placed at the
+/// digest of the node name, a 0xFF byte and the replica number; the versions differ in how the
+/// number is written.
+///
+/// Switching versions moves nearly every position, so an existing cluster has to move all of its
+/// rings over at once, like changing the hasher. New clusters should pick the latest version.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PlacementVersion {
+    /// The replica number as decimal text, counting from 0, so identifiers vary in length and
+    /// replicas 1 and 10 share a prefix. This is the default. It pins the layout of the 0xFF
+    /// separated identifiers above; rings from before the separator was introduced, which hashed
+    /// the bare name for one position and `name-i` for the others, are placed by neither version.
+    #[default]
+    V1,
+    /// The replica number as 4 big-endian bytes: every identifier of a node has the same length
+    /// and no number needs formatting.
+    V2,
+}
+
+impl AverageBasis {
+    // stable byte identifying the basis in encoded rings
+    fn id(&self) -> u8 {
+        match self {
+            AverageBasis::Physical => 1,
+            AverageBasis::Virtual => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<AverageBasis> {
+        match id {
+            1 => Some(AverageBasis::Physical),
+            2 => Some(AverageBasis::Virtual),
+            _ => None,
+        }
+    }
+}
+
+impl PlacementVersion {
+    // stable byte identifying the version in encoded rings
+    fn id(&self) -> u8 {
+        match self {
+            PlacementVersion::V1 => 1,
+            PlacementVersion::V2 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<PlacementVersion> {
+        match id {
+            1 => Some(PlacementVersion::V1),
+            2 => Some(PlacementVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+impl RingWidth {
+    // bytes kept from the digest for each ring position
+    fn bytes(&self) -> usize {
+        match self {
+            RingWidth::Bits32 => 4,
+            RingWidth::Bits64 => 8,
+            RingWidth::Bits128 => 16,
+        }
+    }
+}
+
+/// Optional bookkeeping a ring keeps about its own activity, combined with `|` and picked on the
+/// builder. Each one costs memory, so none is on by default and methods reading a disabled one
+/// return None (or false, for `unassign_key`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Diagnostics(u8);
+
+impl Diagnostics {
+    pub const NONE: Diagnostics = Diagnostics(0);
+    /// Which node each assigned key went to, for `unassign_key`, which also makes assigning an
+    /// already assigned key a no-op. One entry per assigned key.
+    pub const KEY_TRACKING: Diagnostics = Diagnostics(1);
+    /// When each node last received a key, for `last_assigned`. One entry per node.
+    pub const TIMESTAMPS: Diagnostics = Diagnostics(1 << 1);
+    /// Keys `assign_key` rejected, for `take_rejection_log`. One entry per rejection until taken.
+    pub const REJECTION_LOG: Diagnostics = Diagnostics(1 << 2);
+    /// Every topology change, for `topology_log`. One entry per change, kept forever.
+    pub const TOPOLOGY_LOG: Diagnostics = Diagnostics(1 << 3);
+    pub const ALL: Diagnostics = Diagnostics(0b1111);
+
+    /// Whether every diagnostic in `other` is enabled here.
+    pub fn contains(&self, other: Diagnostics) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Diagnostics {
+    type Output = Diagnostics;
+
+    fn bitor(self, other: Diagnostics) -> Diagnostics {
+        Diagnostics(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Diagnostics {
+    fn bitor_assign(&mut self, other: Diagnostics) {
+        self.0 |= other.0;
+    }
+}
+
+/// Configures a `ConsistentHash` before any nodes are added.
+#[derive(Clone)]
+pub struct ConsistentHashBuilder {
+    load_factor: f64,
+    hasher: HashAlgorithm,
+    ring_width: RingWidth,
+    placement_version: PlacementVersion,
+    metrics: Arc<dyn Metrics>,
+    average_basis: AverageBasis,
+    strict: bool,
+    diagnostics: Diagnostics,
+}
+
+impl ConsistentHashBuilder {
+    pub fn new() -> ConsistentHashBuilder {
+        ConsistentHashBuilder {
+            load_factor: 1.0,
+            hasher: HashAlgorithm::default(),
+            ring_width: RingWidth::default(),
+            placement_version: PlacementVersion::default(),
+            metrics: Arc::new(NoopMetrics),
+            average_basis: AverageBasis::default(),
+            strict: false,
+            diagnostics: Diagnostics::NONE,
+        }
+    }
+
+    pub fn load_factor(mut self, load_factor: f64) -> ConsistentHashBuilder {
+        self.load_factor = load_factor;
+        self
+    }
+
+    /// Whether the load factor multiplies the average load per node or per ring position. See
+    /// `AverageBasis`.
+    pub fn average_basis(mut self, average_basis: AverageBasis) -> ConsistentHashBuilder {
+        self.average_basis = average_basis;
+        self
+    }
+
+    pub fn hasher(mut self, hasher: HashAlgorithm) -> ConsistentHashBuilder {
+        self.hasher = hasher;
+        self
+    }
+
+    pub fn ring_width(mut self, ring_width: RingWidth) -> ConsistentHashBuilder {
+        self.ring_width = ring_width;
+        self
+    }
+
+    /// How replica positions are derived from node names. See `PlacementVersion`.
+    pub fn placement_version(mut self, placement_version: PlacementVersion) -> ConsistentHashBuilder {
+        self.placement_version = placement_version;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> ConsistentHashBuilder {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Rejects empty keys with `RingError::EmptyKey` instead of routing them, for callers where
+    /// an empty key always means a bug upstream.
+    pub fn strict(mut self, strict: bool) -> ConsistentHashBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// Bookkeeping the ring records, e.g. `Diagnostics::KEY_TRACKING | Diagnostics::TIMESTAMPS`.
+    pub fn diagnostics(mut self, diagnostics: Diagnostics) -> ConsistentHashBuilder {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    pub fn build(self) -> ConsistentHash {
+        let mut ch = ConsistentHash::with_hasher(self.hasher);
+        ch.load_factor = self.load_factor;
+        ch.average_basis = self.average_basis;
+        ch.ring_width = self.ring_width;
+        ch.placement_version = self.placement_version;
+        ch.metrics = self.metrics;
+        ch.strict = self.strict;
+        ch.diagnostics = self.diagnostics;
+        if self.diagnostics.contains(Diagnostics::KEY_TRACKING) {
+            ch.assignments = Some(HashMap::new());
+        }
+        ch
+    }
+}
+
+impl Default for ConsistentHashBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for ConsistentHash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes_fixture(n: usize) -> Vec<Node> {
+        let mut test_nodes: Vec<Node> = Vec::<Node>::new();
+        for i in 0..n {
+            test_nodes.push(Node::new(format!("test_node_{}", i)));
+        }
+        test_nodes
+    }
+
+    // pinned to MD5 so the expected owners below hold whichever hash-* feature is enabled
+    fn setup(nodes: Vec<Node>, replica_count: u32, load_factor: f64) -> ConsistentHash {
+        setup_with_basis(nodes, replica_count, load_factor, AverageBasis::Physical)
+    }
+
+    fn setup_with_basis(nodes: Vec<Node>, replica_count: u32, load_factor: f64, basis: AverageBasis) -> ConsistentHash {
+        let mut ch: ConsistentHash = ConsistentHash::builder()
+            .load_factor(load_factor)
+            .average_basis(basis)
+            .hasher(HashAlgorithm::Md5)
+            .build();
+
+        for node in nodes.iter() {
+            ch.add_node(node, replica_count);
+        }
+        ch
+    }
+
+    #[test]
+    fn add_nodes() {
+        let nodes_count = 5;
+        let test_nodes = nodes_fixture(nodes_count);
+        let ch = setup(test_nodes, 3, 1.0);
+
+        let ch_size = ch.size();
+        assert!(ch_size == nodes_count * 3, "count mismatch. expected: {}, actual: {}", nodes_count * 3, ch_size);    
+    }
+
+    #[test]
+    fn remove_nodes() {
+        let nodes_count = 7;
+        let test_nodes = nodes_fixture(nodes_count);
+        let mut ch = setup(test_nodes, 5, 1.0);
+
+        let mut ch_size = ch.size();
+        assert!(ch_size == nodes_count * 5, "count mismatch after add_node. expected: {}, actual: {}", nodes_count * 3, ch_size);    
+
+        assert_eq!(ch.remove_node("non_existant".to_string()), None);
+        ch_size = ch.size();
+        assert!(ch_size == nodes_count * 5, "count mismatch after remove_node on non_existant. expected: {}, actual: {}", nodes_count * 5, ch_size);
+
+        assert_eq!(ch.remove_node("test_node_3".to_string()), Some((Node::new("test_node_3".to_string()), 0)));
+        ch_size = ch.size();
+        assert!(ch_size == (nodes_count - 1) * 5, "count mismatch after remove_node. expected: {}, actual: {}", (nodes_count - 1) * 5, ch_size);
+    }
+
+    #[test]
+    fn get_nodes() {
+        let nodes_count = 7;
+        let test_nodes = nodes_fixture(nodes_count);
+        let mut ch = setup(test_nodes, 3, 1.0);
+
+        let mut matched_node = ch.get_node(String::from("test_key1")).unwrap();
+        assert_eq!(matched_node, Node::new(String::from("test_node_3")));
+
+        ch.add_node(&Node::new(String::from("test_node_8")), 3);
+        matched_node = ch.get_node(String::from("test_key1")).unwrap();
+        assert_eq!(matched_node, Node::new(String::from("test_node_3")));
+    }
+
+    #[test]
+    fn assign_key() {
+        let nodes_count = 3;
+        let test_nodes = nodes_fixture(nodes_count);
+        let mut ch = setup(test_nodes, 1, 1.0);
+
+        let matched_node = ch.get_node(String::from("test_key1")).unwrap();
+        assert_eq!(matched_node, Node::new(String::from("test_node_1")));
+
+        assert_eq!(ch.total_load, 0);
+
+        let outcome = ch.assign_key(String::from("test_key1")).unwrap();
+        assert_eq!(outcome.node, matched_node);
+        assert_eq!(outcome.new_load, 1);
+        // one key over three positions puts its node above average and, at a load factor of 1,
+        // at its cap
+        assert!(outcome.above_average);
+        assert!(outcome.near_cap);
+        assert_eq!(ch.total_load, 1);
+        let _ = ch.assign_key(String::from("test_key2"));
+        assert_eq!(ch.total_load, 2);
+        let outcome = ch.assign_key(String::from("test_key3")).unwrap();
+        assert!(!outcome.above_average, "every node carries the average load");
         assert_eq!(ch.total_load, 3);
-        ch.assign_key(String::from("test_key4"));
+        assert_eq!(ch.assign_key(String::from("test_key4")), Err(RingError::Saturated));
         assert_eq!(ch.total_load, 3);
     }
+
+    #[test]
+    fn position_gaps() {
+        let ch = setup(nodes_fixture(4), 3, 1.0);
+
+        let gaps = ch.position_gaps();
+        assert_eq!(gaps.len(), ch.size());
+        assert!(gaps.windows(2).all(|pair| pair[0] <= pair[1]), "gaps are not sorted: {:?}", gaps);
+        // the arcs cover the whole ring exactly once, so they sum to 2^128
+        assert_eq!(gaps.iter().fold(0u128, |acc, gap| acc.wrapping_add(*gap)), 0);
+
+        assert!(ConsistentHash::new().position_gaps().is_empty());
+        let single = setup(nodes_fixture(1), 1, 1.0);
+        assert_eq!(single.position_gaps(), vec![u128::MAX]);
+    }
+
+    #[test]
+    fn choice_window() {
+        let nodes_count = 3;
+        let mut ch = setup(nodes_fixture(nodes_count), 3, 10.0);
+        ch.set_choice_window(nodes_count);
+
+        for i in 0..30 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        for (name, load) in ch.load_per_node.iter() {
+            assert_eq!(*load, 10, "unbalanced load on {}", name);
+        }
+
+        // a window of one keeps the plain first-fit placement
+        let mut first_fit = setup(nodes_fixture(nodes_count), 3, 10.0);
+        let mut windowed = first_fit.clone();
+        windowed.set_choice_window(1);
+        for i in 0..30 {
+            assert_eq!(first_fit.get_node(format!("test_key{}", i)), windowed.get_node(format!("test_key{}", i)));
+            let _ = first_fit.assign_key(format!("test_key{}", i));
+            let _ = windowed.assign_key(format!("test_key{}", i));
+        }
+    }
+
+    #[test]
+    fn try_get_node() {
+        let mut ch = ConsistentHash::new();
+        assert_eq!(ch.try_get_node(String::from("test_key1")), Err(RingError::EmptyRing));
+
+        ch.add_node(&Node::new(String::from("test_node_1")), 1);
+        assert_eq!(ch.try_get_node(String::from("test_key1")), Ok(Node::new(String::from("test_node_1"))));
+
+        let _ = ch.assign_key(String::from("test_key1"));
+        assert_eq!(ch.try_get_node(String::from("test_key2")), Err(RingError::Saturated));
+        assert_eq!(ch.get_node(String::from("test_key2")), None);
+    }
+
+    #[test]
+    fn rebalance_replicas() {
+        let mut ch = setup(nodes_fixture(6), 10, 1.0);
+        ch.remove_node("test_node_2".to_string());
+
+        let total_replicas: u32 = ch.replicas.values().sum();
+        let targets: HashMap<String, f64> = ch.replicas.iter()
+            .map(|(name, &replicas)| (name.clone(), replicas as f64 / total_replicas as f64))
+            .collect();
+        let error_before = balance_error(&ch.ownership_fractions(), &targets);
+
+        let adjustments = ch.rebalance_replicas();
+        assert!(!adjustments.is_empty());
+        for adjustment in adjustments.iter() {
+            assert_eq!(ch.replicas[&adjustment.name], adjustment.new_replicas);
+            assert_ne!(adjustment.old_replicas, adjustment.new_replicas);
+        }
+        let expected_size: u32 = ch.replicas.values().sum();
+        assert_eq!(ch.size(), expected_size as usize);
+        assert!(balance_error(&ch.ownership_fractions(), &targets) < error_before);
+
+        let fractions: f64 = ch.ownership_fractions().values().sum();
+        assert!((fractions - 1.0).abs() < 1e-9, "fractions sum to {}", fractions);
+    }
+
+    #[test]
+    fn replica_handover_matches_moves() {
+        let mut ch = setup(nodes_fixture(4), 3, 1.0);
+        // a pinned node sharing test_node_1's third position, which test_node_1 keeps by name
+        let shared = ch.replica_hashes("test_node_1", 3)[2];
+        ch.add_node_at_positions(&Node::new(String::from("z_pinned")), &[ch.position_bytes(shared), vec![0x10; 16]]);
+        let targets: HashMap<String, f64> = ch.replicas.keys().map(|name| (name.clone(), 0.2)).collect();
+
+        for name in nodes_fixture(4).iter().map(|node| node.get_name().clone()) {
+            let replicas = ch.replicas[&name];
+            for candidate in [replicas - 1, replicas + 1] {
+                let before = ch.ownership_fractions();
+                let handover = ch.replica_handover(&name, &ch.replica_hashes(&name, replicas + 1), replicas, candidate);
+                let mut moved = ch.clone();
+                moved.move_replicas(&name, candidate);
+                let after = moved.ownership_fractions();
+                let predicted = balance_error(&before, &targets) + handover.error_change(&before, &targets);
+                assert!((balance_error(&after, &targets) - predicted).abs() < 1e-9, "{} to {} replicas", name, candidate);
+                let mut applied = before.clone();
+                handover.apply(&mut applied);
+                for (node, fraction) in after.iter() {
+                    assert!((applied[node] - fraction).abs() < 1e-9, "{} to {} replicas, {} owns", name, candidate, node);
+                }
+            }
+        }
+
+        let mut single = ConsistentHash::new();
+        single.add_node(&Node::new(String::from("test_node_0")), 1);
+        let handover = single.replica_handover("test_node_0", &single.replica_hashes("test_node_0", 1), 1, 0);
+        assert_eq!((handover.to, handover.fraction), (None, 1.0));
+    }
+
+    #[test]
+    fn remove_node_with_migration() {
+        let before = setup(nodes_fixture(5), 4, 1000.0);
+        let mut after = before.clone();
+
+        let migrations = after.remove_node_with_migration("test_node_3");
+        assert!(!migrations.is_empty());
+        assert_eq!(after.size(), 4 * 4);
+
+        let in_arc = |hash: u128, migration: &Migration| {
+            let start = position_value(&migration.start);
+            let end = position_value(&migration.end);
+            if start < end { hash > start && hash <= end } else { hash > start || hash <= end }
+        };
+        for i in 0..200 {
+            let key = format!("test_key{}", i);
+            let hash = position_value(&md5::compute(&key).to_vec());
+            let old_owner = before.get_node(key.clone()).unwrap();
+            let new_owner = after.get_node(key.clone()).unwrap();
+            match migrations.iter().find(|migration| in_arc(hash, migration)) {
+                None => assert_eq!(old_owner, new_owner, "{} moved outside a reported arc", key),
+                Some(migration) => {
+                    assert_eq!(old_owner, migration.from);
+                    assert_eq!(new_owner, migration.to);
+                }
+            }
+        }
+
+        let mut single = setup(nodes_fixture(1), 3, 1.0);
+        assert!(single.remove_node_with_migration("test_node_0").is_empty());
+        assert!(single.remove_node_with_migration("non_existant").is_empty());
+    }
+
+    #[test]
+    fn distribution_variance() {
+        assert_eq!(ConsistentHash::new().distribution_variance(), 0.0);
+        assert!(setup(nodes_fixture(1), 5, 1.0).distribution_variance() < 1e-12);
+
+        let sparse = setup(nodes_fixture(5), 1, 1.0).distribution_variance();
+        let dense = setup(nodes_fixture(5), 200, 1.0).distribution_variance();
+        assert!(dense < sparse, "expected more replicas to even out the ring: {} vs {}", dense, sparse);
+        assert!(dense < 0.05, "variance with 200 replicas too high: {}", dense);
+    }
+
+    #[test]
+    fn assign_key_with_decay() {
+        let mut ch = setup(nodes_fixture(2), 1, 1.0);
+        ch.set_half_life(Duration::from_secs(1));
+        let start = Instant::now();
+
+        assert_eq!(ch.assign_key_with_decay(String::from("test_key1"), start), Ok(()));
+        assert_eq!(ch.assign_key_with_decay(String::from("test_key2"), start), Ok(()));
+        assert_eq!(ch.decayed_total, 2.0);
+        // both nodes are at the cap until some of their load decays away
+        assert_eq!(ch.assign_key_with_decay(String::from("test_key3"), start), Err(RingError::Saturated));
+        assert_eq!(ch.decayed_total, 2.0);
+        assert_eq!(ch.rejections, 1);
+
+        assert_eq!(ch.assign_key_with_decay(String::from("test_key3"), start + Duration::from_secs(1)), Ok(()));
+        assert!((ch.decayed_total - 2.0).abs() < 1e-9, "decayed total: {}", ch.decayed_total);
+        let mut loads: Vec<f64> = ["test_node_0", "test_node_1"].iter().map(|name| ch.decayed_load(name).unwrap()).collect();
+        loads.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!((loads[0] - 0.5).abs() < 1e-9 && (loads[1] - 1.5).abs() < 1e-9, "decayed loads: {:?}", loads);
+
+        // plain assignment counts are not part of the decayed accounting
+        assert_eq!(ch.total_load, 0);
+        assert_eq!(ch.decayed_load("non_existant"), None);
+
+        // without any decay in between, the decayed cap admits what the plain cap would
+        let mut plain = setup(nodes_fixture(3), 8, 1.25);
+        let mut decayed = plain.clone();
+        decayed.set_half_life(Duration::from_secs(1));
+        let keys: Vec<String> = (0..30).map(|i| format!("test_key{}", i)).collect();
+        let accepted = keys.iter().filter(|key| plain.assign_key(key.to_string()).is_ok()).count();
+        let accepted_decayed =
+            keys.iter().filter(|key| decayed.assign_key_with_decay(key.to_string(), start).is_ok()).count();
+        assert_eq!(accepted_decayed, accepted);
+        assert_eq!(decayed.decayed_total, accepted as f64);
+    }
+
+    #[test]
+    fn owner_of_range() {
+        let ch = setup(nodes_fixture(4), 3, 1.0);
+        let positions = ch.sorted_positions();
+
+        let owners = ch.owner_of_range(&positions[0].0, &positions[2].0);
+        let mut expected = vec![positions[0].1.clone()];
+        if positions[1].1 != positions[0].1 {
+            expected.push(positions[1].1.clone());
+        }
+        assert_eq!(owners, expected);
+
+        assert!(ch.owner_of_range(&positions[3].0, &positions[3].0).is_empty());
+        assert_eq!(ch.owner_of_range(&[0u8; 16], &[0xffu8; 16]).len(), 4);
+
+        // wrapping past the top of the ring only picks up the last and first positions
+        let last = &positions[positions.len() - 1];
+        let wrapped = ch.owner_of_range(&last.0, &positions[1].0);
+        assert_eq!(wrapped[0], *last.1);
+        assert!(wrapped.contains(positions[0].1));
+    }
+
+    #[test]
+    fn list_nodes_deduped() {
+        let nodes_count = 4;
+        let ch = setup(nodes_fixture(nodes_count), 3, 1.0);
+
+        assert_eq!(ch.list_nodes().unwrap().len(), nodes_count * 3);
+        let mut deduped = ch.list_nodes_deduped().unwrap();
+        deduped.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        assert_eq!(deduped, nodes_fixture(nodes_count));
+
+        assert_eq!(ConsistentHash::new().list_nodes_deduped(), None);
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn sha256_hasher() {
+        let mut md5_ring = ConsistentHash::with_hasher(HashAlgorithm::Md5);
+        let mut sha256_ring = ConsistentHash::with_hasher(HashAlgorithm::Sha256);
+        for node in nodes_fixture(3).iter() {
+            md5_ring.add_node(node, 3);
+            sha256_ring.add_node(node, 3);
+        }
+
+        assert_eq!(sha256_ring.size(), 9);
+        assert!(sha256_ring.nodes.keys().all(|hash| sha256_ring.position_bytes(*hash).len() == 16));
+        assert_ne!(md5_ring.nodes.keys().collect::<Vec<_>>(), sha256_ring.nodes.keys().collect::<Vec<_>>());
+        assert!(sha256_ring.get_node(String::from("test_key1")).is_some());
+    }
+
+    #[test]
+    fn audit() {
+        let mut ch = setup(nodes_fixture(4), 3, 1.25);
+        for i in 0..10 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        ch.remove_node("test_node_2".to_string());
+        assert_eq!(ch.audit(), Ok(()));
+
+        ch.nodes.insert(0, Node::new(String::from("test_node_1")));
+        let missing = ch.node_hashes("test_node_3", 3).remove(1);
+        ch.nodes.remove(&missing);
+        ch.total_load += 1;
+        ch.load_per_node.remove("test_node_0");
+        let errors = ch.audit().unwrap_err();
+        assert!(errors.contains(&AuditError::MissingLoad(String::from("test_node_0"))));
+        assert!(errors.iter().any(|error| matches!(error, AuditError::TotalLoadMismatch { .. })));
+        assert!(errors.contains(&AuditError::PositionCountMismatch {
+            node: String::from("test_node_1"),
+            expected: 3,
+            actual: 4,
+        }));
+        assert!(errors.contains(&AuditError::OrphanPosition { node: String::from("test_node_1"), position: vec![0u8; 16] }));
+        assert!(errors.contains(&AuditError::MissingPosition { node: String::from("test_node_3"), position: missing.to_be_bytes().to_vec() }));
+    }
+
+    #[test]
+    fn hash_tags() {
+        assert_eq!(hash_tag("user:{42}:profile"), "42");
+        assert_eq!(hash_tag("user:{42}:{7}"), "42");
+        assert_eq!(hash_tag("user:{}:profile"), "user:{}:profile");
+        assert_eq!(hash_tag("user:{42"), "user:{42");
+        assert_eq!(hash_tag("user:42}{"), "user:42}{");
+
+        let mut ch = setup(nodes_fixture(5), 10, 1000.0);
+        ch.set_hash_tag_extractor(hash_tag);
+        let owner = ch.get_node(String::from("42")).unwrap();
+        for key in ["user:{42}:profile", "user:{42}:settings", "{42}"] {
+            assert_eq!(ch.get_node(key.to_string()).unwrap(), owner, "{} not co-located", key);
+        }
+        assert_eq!(ch.key_hash("user:{}:profile"), position_value(&ch.hasher.digest(b"user:{}:profile")));
+
+        // extractors returning nothing fall back to the whole key
+        ch.set_hash_tag_extractor(|_key| "");
+        assert_eq!(ch.key_hash("user:{42}:profile"), position_value(&ch.hasher.digest(b"user:{42}:profile")));
+    }
+
+    #[test]
+    fn neighbors() {
+        assert_eq!(ConsistentHash::new().neighbors(String::from("test_key1")), (None, None));
+        let single = setup(nodes_fixture(1), 3, 1.0);
+        assert_eq!(single.neighbors(String::from("test_key1")), (Some(Node::new(String::from("test_node_0"))), None));
+
+        let ch = setup(nodes_fixture(5), 4, 1000.0);
+        let positions: Vec<(u128, &Node)> = ch.nodes.iter().map(|(hash, node)| (*hash, node)).collect();
+        for i in 0..50 {
+            let key = format!("test_key{}", i);
+            let (successor, predecessor) = ch.neighbors(key.clone());
+            let successor = successor.unwrap();
+            let predecessor = predecessor.unwrap();
+            assert_eq!(successor, ch.get_node(key.clone()).unwrap());
+            assert_ne!(successor, predecessor);
+
+            // nothing between the predecessor's last position and the key belongs to a third node
+            let hash = ch.key_hash(&key);
+            let start = positions.iter().rposition(|(position, _)| *position < hash).unwrap_or(positions.len() - 1);
+            let mut index = start;
+            while positions[index].1 == &successor {
+                index = (index + positions.len() - 1) % positions.len();
+            }
+            assert_eq!(positions[index].1, &predecessor);
+        }
+    }
+
+    #[test]
+    fn from_weighted() {
+        let weighted: Vec<(Node, u32)> = nodes_fixture(3).into_iter().zip([1, 2, 5]).collect();
+        let ch = ConsistentHash::from_weighted(&weighted, 1.25, HashAlgorithm::Md5);
+
+        assert_eq!(ch.size(), 8);
+        assert_eq!(ch.load_factor, 1.25);
+        assert_eq!(ch.replicas[&String::from("test_node_2")], 5);
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn replica_identifiers_do_not_collide_with_names() {
+        let mut ch = ConsistentHash::new();
+        ch.add_node(&Node::new(String::from("a")), 3);
+        ch.add_node(&Node::new(String::from("a-1")), 3);
+
+        assert_eq!(ch.size(), 6);
+        assert_eq!(ch.nodes.values().filter(|node| node.get_name() == "a").count(), 3);
+        assert_eq!(ch.nodes.values().filter(|node| node.get_name() == "a-1").count(), 3);
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn headroom() {
+        let mut ch = setup(nodes_fixture(3), 1, 2.0);
+        assert!(ch.headroom().values().all(|&headroom| headroom == 2));
+
+        for i in 0..4 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        // average 4/3 scaled by 2.0 caps every node at 3 keys
+        for (name, headroom) in ch.headroom() {
+            assert_eq!(headroom, 3 - ch.load_per_node[&name] as i64);
+        }
+
+        ch.load_factor = 0.5;
+        let loaded = ch.load_per_node.iter().max_by_key(|(_, load)| **load).unwrap().0.clone();
+        assert!(ch.headroom()[&loaded] < 0);
+    }
+
+    #[test]
+    fn with_node() {
+        let ch = setup(nodes_fixture(3), 3, 1.0);
+        let key = String::from("test_key1");
+
+        let name = ch.with_node(key.clone(), |node| node.get_name().clone());
+        assert_eq!(name, ch.get_node(key).map(|node| node.get_name().clone()));
+
+        let mut called = false;
+        assert_eq!(ConsistentHash::new().with_node(String::from("test_key1"), |_node| called = true), None);
+        assert!(!called);
+    }
+
+    #[test]
+    fn unassign_key() {
+        let mut ch = setup(nodes_fixture(3), 3, 1.25);
+        let _ = ch.assign_key(String::from("test_key1"));
+        assert!(!ch.unassign_key(String::from("test_key1")), "untracked keys can't be released");
+
+        ch.enable_key_tracking();
+        let _ = ch.assign_key(String::from("test_key2"));
+        let node = ch.assignments.as_ref().unwrap()[&String::from("test_key2")].0.clone();
+        let load = ch.load_per_node[&node];
+        assert!(ch.unassign_key(String::from("test_key2")));
+        assert_eq!(ch.load_per_node[&node], load - 1);
+        assert_eq!(ch.total_load, 1);
+        assert!(!ch.unassign_key(String::from("test_key2")));
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn add_existing_node_keeps_load() {
+        let mut ch = setup(nodes_fixture(2), 5, 1.25);
+        for i in 0..4 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        ch.add_node(&Node::new(String::from("test_node_0")), 2);
+
+        assert_eq!(ch.size(), 7);
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn random_operations_keep_invariants() {
+        // same operations as the fuzz target, driven by a fixed xorshift sequence
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut ch = ConsistentHash::with_load_factor(1.25);
+        ch.enable_key_tracking();
+        for _ in 0..2000 {
+            let op = next();
+            let node = format!("node_{}", (op >> 8) % 8);
+            let key = format!("key_{}", (op >> 16) % 64);
+            match op % 5 {
+                0 => ch.add_node(&Node::new(node), ((op >> 32) % 16) as u32),
+                1 => {
+                    ch.remove_node(node);
+                }
+                2 => {
+                    let _ = ch.assign_key(key);
+                }
+                3 => {
+                    ch.unassign_key(key);
+                }
+                _ => {
+                    ch.get_node(key);
+                }
+            }
+            assert_eq!(ch.audit(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn ring_width() {
+        for (width, bytes) in [(RingWidth::Bits32, 4), (RingWidth::Bits64, 8), (RingWidth::Bits128, 16)] {
+            let mut ch = ConsistentHash::builder().load_factor(1.25).ring_width(width).build();
+            for node in nodes_fixture(4).iter() {
+                ch.add_node(node, 5);
+            }
+            assert_eq!(ch.size(), 20);
+            // positions round-trip through `bytes` bytes, so nothing is set past the ring width
+            assert!(ch.nodes.keys().all(|hash| ch.position_bytes(*hash).len() == bytes));
+            assert!(ch.nodes.keys().all(|hash| position_value(&ch.position_bytes(*hash)) == *hash));
+            let key_hash = ch.key_hash("test_key1");
+            assert_eq!(position_value(&ch.position_bytes(key_hash)), key_hash);
+            assert!(ch.get_node(String::from("test_key1")).is_some());
+            assert_eq!(ch.audit(), Ok(()));
+
+            let fractions: f64 = ch.ownership_fractions().values().sum();
+            assert!((fractions - 1.0).abs() < 1e-9, "fractions sum to {} at {:?}", fractions, width);
+        }
+
+        // narrow positions are prefixes of the full width ones
+        let full = setup(nodes_fixture(4), 5, 1.0);
+        let mut narrow = ConsistentHash::builder().hasher(HashAlgorithm::Md5).ring_width(RingWidth::Bits64).build();
+        for node in nodes_fixture(4).iter() {
+            narrow.add_node(node, 5);
+        }
+        let prefixes: Vec<u128> = full.nodes.keys().map(|hash| hash >> 64 << 64).collect();
+        assert_eq!(narrow.nodes.keys().cloned().collect::<Vec<_>>(), prefixes);
+    }
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl Metrics for RecordingMetrics {
+        fn on_assign(&self, node: &Node, new_load: u64) {
+            self.events.lock().unwrap().push(format!("assign {} {}", node.get_name(), new_load));
+        }
+
+        fn on_reject(&self, key: &str) {
+            self.events.lock().unwrap().push(format!("reject {}", key));
+        }
+
+        fn on_add_node(&self, name: &str) {
+            self.events.lock().unwrap().push(format!("add {}", name));
+        }
+
+        fn on_remove_node(&self, name: &str) {
+            self.events.lock().unwrap().push(format!("remove {}", name));
+        }
+    }
+
+    #[test]
+    fn metrics_hooks() {
+        let metrics = Arc::new(RecordingMetrics::default());
+        let mut ch = ConsistentHash::builder().metrics(metrics.clone()).build();
+
+        ch.add_node(&Node::new(String::from("test_node_0")), 2);
+        let _ = ch.assign_key(String::from("test_key1"));
+        let _ = ch.assign_key(String::from("test_key2"));
+        ch.remove_node(String::from("non_existant"));
+        ch.remove_node(String::from("test_node_0"));
+
+        assert_eq!(*metrics.events.lock().unwrap(), vec![
+            "add test_node_0",
+            "assign test_node_0 1",
+            "reject test_key2",
+            "remove test_node_0",
+        ]);
+    }
+
+    #[test]
+    fn plan_scale() {
+        let ch = setup(nodes_fixture(4), 10, 1.0);
+        let target = vec![
+            (String::from("test_node_0"), 10),
+            (String::from("test_node_1"), 20),
+            (String::from("test_node_3"), 10),
+            (String::from("test_node_4"), 10),
+        ];
+
+        let plan = ch.plan_scale(&target);
+        let ops: Vec<ScaleOp> = plan.steps.iter().map(|step| step.op.clone()).collect();
+        assert_eq!(ops, vec![
+            ScaleOp::Add { name: String::from("test_node_4"), replicas: 10 },
+            ScaleOp::SetReplicas { name: String::from("test_node_1"), from: 10, to: 20 },
+            ScaleOp::Remove { name: String::from("test_node_2") },
+        ]);
+        let fractions = ch.ownership_fractions();
+        // every step moves some, but not all, of the keyspace
+        assert!(plan.steps.iter().all(|step| step.moved_fraction > 0.0 && step.moved_fraction < 1.0));
+        assert!(plan.total_moved_fraction <= plan.steps.iter().map(|step| step.moved_fraction).sum::<f64>() + 1e-9);
+        assert!(plan.total_moved_fraction >= fractions["test_node_2"] - 1e-9);
+        assert_eq!(ch.size(), 40, "planning must not touch the ring");
+
+        assert!(ch.plan_scale(&[
+            (String::from("test_node_0"), 10),
+            (String::from("test_node_1"), 10),
+            (String::from("test_node_2"), 10),
+            (String::from("test_node_3"), 10),
+        ]).steps.is_empty());
+
+        let mut removed = ch.clone();
+        removed.remove_node(String::from("test_node_2"));
+        assert!((ch.moved_fraction(&removed) - fractions["test_node_2"]).abs() < 1e-9);
+    }
+
+    #[test]
+    fn empty_keys() {
+        let mut ch = setup(nodes_fixture(5), 3, 1.25);
+        assert_eq!(ch.get_node(String::new()), Some(Node::new(String::from("test_node_2"))));
+        let _ = ch.assign_key(String::new());
+        assert_eq!(ch.total_load, 1);
+
+        let mut strict = ConsistentHash::builder().load_factor(1.25).hasher(HashAlgorithm::Md5).strict(true).build();
+        for node in nodes_fixture(5).iter() {
+            strict.add_node(node, 3);
+        }
+        assert_eq!(strict.try_get_node(String::new()), Err(RingError::EmptyKey));
+        assert_eq!(strict.get_node(String::new()), None);
+        let _ = strict.assign_key(String::new());
+        assert_eq!(strict.total_load, 0);
+        assert!(strict.get_node(String::from("test_key1")).is_some());
+    }
+
+    #[test]
+    fn suggest_replicas() {
+        assert_eq!(ConsistentHash::suggest_replicas(1, 0.1), 1);
+        assert_eq!(ConsistentHash::suggest_replicas(10, 0.0), u32::MAX);
+        assert_eq!(ConsistentHash::suggest_replicas(10, 0.1), 90);
+        assert_eq!(ConsistentHash::suggest_replicas(2, 10.0), 1);
+
+        for (node_count, target_cv) in [(10, 0.1), (20, 0.05)] {
+            let replicas = ConsistentHash::suggest_replicas(node_count, target_cv);
+            // average over a few differently named clusters to smooth out sampling noise
+            let mut total_cv = 0.0;
+            for cluster in 0..5 {
+                let mut ch = ConsistentHash::new();
+                for i in 0..node_count {
+                    ch.add_node(&Node::new(format!("cluster_{}_node_{}", cluster, i)), replicas);
+                }
+                total_cv += ch.distribution_variance().sqrt();
+            }
+            let cv = total_cv / 5.0;
+            assert!(cv > target_cv * 0.6 && cv < target_cv * 1.4, "cv {} too far from target {}", cv, target_cv);
+        }
+    }
+
+    #[test]
+    fn capacity_weights() {
+        let mut ch = setup(nodes_fixture(3), 1, 1.0);
+        assert!(ch.set_capacity_weight("test_node_0", 3.0));
+        assert!(!ch.set_capacity_weight("non_existant", 3.0));
+
+        for i in 0..100 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        // with the default weight of 1.0 the other nodes are held to the average
+        let loads = |name: &str| ch.load_per_node[&String::from(name)];
+        assert!(loads("test_node_0") > loads("test_node_1") && loads("test_node_0") > loads("test_node_2"));
+        for (name, headroom) in ch.headroom() {
+            assert!(headroom >= 0, "{} is over its cap", name);
+        }
+        let avg = ch.total_load as f64 / 3.0;
+        assert!(loads("test_node_0") as f64 <= (avg * 3.0).ceil());
+        assert!(loads("test_node_1") as f64 <= avg.ceil());
+    }
+
+    #[test]
+    fn load_policy() {
+        let mut ch = setup(nodes_fixture(1), 1, 1.0);
+        let _ = ch.assign_key(String::from("test_key1"));
+        let node = Some(Node::new(String::from("test_node_0")));
+
+        assert_eq!(ch.get_node_with_policy(String::from("test_key2"), LoadPolicy::Strict), None);
+        assert_eq!(ch.get_node_with_policy(String::from("test_key2"), LoadPolicy::Relaxed(2.0)), node);
+        assert_eq!(ch.get_node_with_policy(String::from("test_key2"), LoadPolicy::Ignore), node);
+        assert_eq!(ConsistentHash::new().get_node_with_policy(String::from("test_key2"), LoadPolicy::Ignore), None);
+        // the ring's own load factor is unchanged
+        assert_eq!(ch.get_node(String::from("test_key2")), None);
+    }
+
+    #[test]
+    fn key_builder() {
+        let split_late = KeyBuilder::new().field("a").field("bc").build();
+        let split_early = KeyBuilder::new().field("ab").field("c").build();
+        assert_ne!(split_late, split_early);
+        assert_ne!(KeyBuilder::new().field("1:a").build(), KeyBuilder::new().field("1").field("a").build());
+        assert_eq!(KeyBuilder::new().build(), "");
+
+        let ch = setup(nodes_fixture(3), 3, 1.0);
+        let key = KeyBuilder::new().field("tenant").field("user").build();
+        assert_eq!(ch.get_node(key.clone()), ch.get_node(String::from("6:tenant4:user")));
+    }
+
+    #[test]
+    fn load_skew() {
+        let mut ch = setup(nodes_fixture(4), 10, 1000.0);
+        ch.add_node(&Node::new(String::from("no_replicas")), 0);
+        assert!(ch.load_skew().is_empty());
+
+        for i in 0..400 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        let skew = ch.load_skew();
+        let fractions = ch.ownership_fractions();
+        assert_eq!(skew.len(), 4);
+        assert!(!skew.contains_key("no_replicas"));
+        for (name, ratio) in skew.iter() {
+            let expected = ch.load_per_node[name] as f64 / (400.0 * fractions[name]);
+            assert!((ratio - expected).abs() < 1e-9);
+            // with a loose cap placement follows ownership
+            assert!(*ratio > 0.7 && *ratio < 1.3, "{} skewed by {}", name, ratio);
+        }
+    }
+
+    #[test]
+    fn remove_node_returns_final_load() {
+        let mut ch = setup(nodes_fixture(3), 3, 1.25);
+        for i in 0..9 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        let load = ch.load_per_node["test_node_1"];
+        let total_load = ch.total_load;
+        assert!(load > 0);
+
+        assert_eq!(ch.remove_node(String::from("test_node_1")), Some((Node::new(String::from("test_node_1")), load)));
+        assert_eq!(ch.total_load, total_load - load);
+        assert_eq!(ch.remove_node(String::from("test_node_1")), None);
+    }
+
+    #[test]
+    fn router() {
+        let mut anchor = AnchorHash::new(8);
+        for node in nodes_fixture(3).iter() {
+            anchor.add_node(node);
+        }
+        let routers: Vec<Box<dyn Router>> = vec![Box::new(setup(nodes_fixture(3), 3, 1.0)), Box::new(anchor)];
+        for router in routers.iter() {
+            let node = router.route(String::from("test_key1")).unwrap();
+            assert!(nodes_fixture(3).contains(&node));
+        }
+    }
+
+    #[test]
+    fn last_assigned() {
+        let mut ch = ConsistentHash::builder().load_factor(1.25).diagnostics(Diagnostics::TIMESTAMPS).build();
+        for node in nodes_fixture(2).iter() {
+            ch.add_node(node, 3);
+        }
+        assert_eq!(ch.last_assigned("test_node_0"), None);
+
+        let before = Instant::now();
+        let _ = ch.assign_key(String::from("test_key1"));
+        let node = ch.get_node_with_policy(String::from("test_key1"), LoadPolicy::Ignore).unwrap();
+        let assigned = ch.last_assigned(node.get_name()).unwrap();
+        assert!(assigned >= before && assigned <= Instant::now());
+
+        ch.remove_node(node.get_name().clone());
+        assert_eq!(ch.last_assigned(node.get_name()), None);
+    }
+
+    #[test]
+    fn coverage_gaps() {
+        let ch = setup(nodes_fixture(4), 5, 1.0);
+        let gaps = ch.position_gaps();
+        let median = gaps[gaps.len() / 2];
+
+        let wide = ch.coverage_gaps(median);
+        assert_eq!(wide.len(), gaps.iter().filter(|gap| **gap > median).count());
+        for (start, end, node) in wide.iter() {
+            assert_eq!(ch.nodes[&position_value(end)], *node);
+            assert!(position_value(end).wrapping_sub(position_value(start)) > median);
+        }
+        assert_eq!(ch.coverage_gaps(0).len(), ch.size());
+        assert!(ch.coverage_gaps(u128::MAX).is_empty());
+    }
+
+    #[test]
+    fn remove_nodes_where() {
+        let mut ch = setup(nodes_fixture(6), 3, 1.25);
+        for i in 0..12 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+
+        let mut removed = ch.remove_nodes_where(|node| node.get_name().ends_with('1') || node.get_name().ends_with('4'));
+        removed.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        assert_eq!(removed, vec![Node::new(String::from("test_node_1")), Node::new(String::from("test_node_4"))]);
+        assert_eq!(ch.size(), 4 * 3);
+        assert!(ch.audit().is_ok());
+
+        assert!(ch.remove_nodes_where(|_| false).is_empty());
+        assert_eq!(ch.remove_nodes_where(|_| true).len(), 4);
+        assert_eq!(ch.size(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "xxhash")]
+    fn xxhash_hasher() {
+        let mut ch = ConsistentHash::with_hasher(HashAlgorithm::XxHash);
+        for node in nodes_fixture(3).iter() {
+            ch.add_node(node, 3);
+        }
+        assert_eq!(ch.size(), 9);
+        assert!(ch.nodes.keys().all(|hash| ch.position_bytes(*hash).len() == 16));
+        assert!(ch.get_node(String::from("test_key1")).is_some());
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn assign_or_overflow() {
+        let mut ch = setup(nodes_fixture(3), 1, 1.0);
+        let overflow = Node::new(String::from("overflow"));
+        ch.set_overflow_node(overflow.clone());
+
+        let mut overflowed = 0;
+        for i in 0..6 {
+            let node = ch.assign_or_overflow(format!("test_key{}", i)).unwrap();
+            if node == overflow {
+                overflowed += 1;
+            }
+        }
+        assert!(overflowed > 0);
+        assert_eq!(ch.overflow_load(), overflowed);
+        assert_eq!(ch.total_load + overflowed, 6);
+        // overflowed keys were placed, so none of them count as rejected
+        assert_eq!(ch.rejections, 0);
+        assert!(ch.get_node(String::from("test_key1")).is_none_or(|node| node != overflow));
+
+        let mut empty = ConsistentHash::new();
+        assert_eq!(empty.assign_or_overflow(String::from("test_key1")), Err(RingError::EmptyRing));
+        assert_eq!(empty.rejections, 1);
+        empty.set_overflow_node(overflow.clone());
+        assert_eq!(empty.assign_or_overflow(String::from("test_key1")), Ok(overflow));
+    }
+
+    #[test]
+    fn nodes_by_capacity() {
+        let mut ch = setup(nodes_fixture(4), 3, 2.0);
+        for i in 0..10 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+
+        let nodes = ch.nodes_by_capacity();
+        assert_eq!(nodes.len(), 4);
+        assert!(nodes.windows(2).all(|pair| pair[0].1 >= pair[1].1), "not sorted: {:?}", nodes);
+        let headroom = ch.headroom();
+        for (node, room) in nodes.iter() {
+            assert_eq!(headroom[node.get_name()], *room);
+        }
+        assert!(ConsistentHash::new().nodes_by_capacity().is_empty());
+    }
+
+    #[test]
+    fn check_load_lookahead() {
+        let mut ch = setup(nodes_fixture(2), 1, 2.0);
+        let _ = ch.assign_key(String::from("test_key1"));
+        // one key over two positions averages 0.5, so each node is capped at 1 key
+        let (loaded, idle) = match ch.load_per_node["test_node_0"] {
+            1 => ("test_node_0", "test_node_1"),
+            _ => ("test_node_1", "test_node_0"),
+        };
+        assert!(!ch.check_load(loaded, 1));
+        assert!(ch.check_load(loaded, 0));
+        assert!(ch.check_load(idle, 1));
+        assert!(!ch.check_load(idle, 2));
+        assert!(!ch.check_load(idle, u64::MAX));
+        assert!(!ch.check_load("test_node_9", 0));
+    }
+
+    #[test]
+    fn recompute_load() {
+        let keys: Vec<String> = (0..20).map(|i| format!("test_key{}", i)).collect();
+        let mut live = setup(nodes_fixture(4), 3, 1.25);
+        for key in keys.iter() {
+            let _ = live.assign_key(key.clone());
+        }
+
+        let mut restored = setup(nodes_fixture(4), 3, 1.25);
+        let _ = restored.assign_key(String::from("stale_key"));
+        restored.recompute_load(&keys);
+        assert_eq!(restored.total_load, live.total_load);
+        assert_eq!(restored.load_per_node, live.load_per_node);
+        assert_eq!(restored.audit(), Ok(()));
+    }
+
+    #[test]
+    fn get_placement() {
+        let ch = setup(nodes_fixture(5), 4, 1.25);
+        let placement = ch.get_placement(String::from("test_key1"), 3).unwrap();
+        assert_eq!(Some(placement.primary.clone()), ch.neighbors(String::from("test_key1")).0);
+        assert_eq!(placement.replicas.len(), 2);
+        assert!(!placement.replicas.contains(&placement.primary));
+        assert_ne!(placement.replicas[0], placement.replicas[1]);
+
+        assert_eq!(ch.get_placement(String::from("test_key1"), 10).unwrap().replicas.len(), 4);
+        assert_eq!(ch.get_placement(String::from("test_key1"), 0), None);
+        assert_eq!(ConsistentHash::new().get_placement(String::from("test_key1"), 3), None);
+    }
+
+    #[test]
+    fn simulate() {
+        let ch = setup(nodes_fixture(4), 1, 1.25);
+        let report = ch.simulate((0..400).map(|i| format!("test_key{}", i)));
+        assert_eq!(ch.total_load, 0, "the ring itself is untouched");
+        assert_eq!(report.per_node.len(), 4);
+        assert_eq!(report.per_node.values().sum::<u64>() + report.rejected, 400);
+        // the bounded-load cap keeps every node within the load factor of the mean
+        assert!(report.max_over_mean >= 1.0 && report.max_over_mean <= 1.25 + 0.01, "{:?}", report);
+        assert_eq!(report.rejected, 0);
+        assert!(report.coefficient_of_variation > 0.0);
+
+        let empty = ConsistentHash::new().simulate((0..3).map(|i| format!("test_key{}", i)));
+        assert_eq!(empty.rejected, 3);
+        assert_eq!(empty.max_over_mean, 0.0);
+    }
+
+    #[test]
+    fn sorted_positions() {
+        let ch = setup(nodes_fixture(4), 5, 1.25);
+        let positions = ch.sorted_positions();
+        assert_eq!(positions.len(), ch.size());
+        assert!(positions.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        for i in 0..20 {
+            let key = format!("test_key{}", i);
+            let hash = ch.position_bytes(ch.key_hash(&key));
+            let index = positions.partition_point(|(position, _)| *position < hash) % positions.len();
+            assert_eq!(Some(positions[index].1.clone()), ch.neighbors(key).0);
+        }
+    }
+
+    #[test]
+    fn scan_limit() {
+        let mut ch = setup(nodes_fixture(3), 1, 1.0);
+        for i in 0..3 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        // every node now sits at its cap
+        assert_eq!(ch.try_get_node(String::from("test_key9")), Err(RingError::Saturated));
+        ch.set_scan_limit(2);
+        assert_eq!(ch.try_get_node(String::from("test_key9")), Err(RingError::ScanLimitExceeded));
+        assert_eq!(ch.assign_key(String::from("test_key9")), Err(RingError::ScanLimitExceeded));
+
+        // a node with room within the limit is still found
+        let owner = ch.neighbors(String::from("test_key9")).0.unwrap();
+        ch.set_capacity_weight(owner.get_name(), 10.0);
+        assert_eq!(ch.try_get_node(String::from("test_key9")), Ok(owner));
+    }
+
+    #[test]
+    fn node_states() {
+        let mut ch = setup(nodes_fixture(3), 3, 100.0);
+        let owner = ch.get_node(String::from("test_key1")).unwrap();
+        assert_eq!(ch.node_state(owner.get_name()), Some(NodeState::Healthy));
+
+        assert!(ch.set_node_state(owner.get_name(), NodeState::Unhealthy));
+        let standby = ch.get_node(String::from("test_key1")).unwrap();
+        assert_ne!(standby, owner);
+        for i in 0..20 {
+            assert_ne!(ch.get_node(format!("test_key{}", i)), Some(owner.clone()));
+        }
+
+        // degraded nodes are passed over while a healthy node has room
+        ch.set_node_state(owner.get_name(), NodeState::Degraded);
+        assert_eq!(ch.get_node(String::from("test_key1")), Some(standby.clone()));
+        for node in nodes_fixture(3).iter().filter(|node| **node != owner) {
+            ch.set_node_state(node.get_name(), NodeState::Unhealthy);
+        }
+        assert_eq!(ch.get_node(String::from("test_key1")), Some(owner.clone()));
+
+        ch.set_node_state(owner.get_name(), NodeState::Healthy);
+        assert_eq!(ch.node_states.len(), 2);
+        assert!(!ch.set_node_state("test_node_9", NodeState::Degraded));
+        assert_eq!(ch.node_state("test_node_9"), None);
+    }
+
+    #[test]
+    fn merge() {
+        let mut ch = setup(nodes_fixture(3), 3, 1.25);
+        let _ = ch.assign_key(String::from("test_key1"));
+        let mut standby = setup(nodes_fixture(2), 3, 1.25);
+        standby.add_node(&Node::new(String::from("standby_node")), 5);
+        standby.set_capacity_weight("standby_node", 2.0);
+
+        assert_eq!(ch.merge(&standby), Ok(()));
+        assert_eq!(ch.size(), 3 * 3 + 5);
+        assert_eq!(ch.capacity_weight("standby_node"), 2.0);
+        assert_eq!(ch.total_load, 1);
+        assert_eq!(ch.audit(), Ok(()));
+
+        let mut conflicting = setup(nodes_fixture(1), 4, 1.25);
+        conflicting.add_node(&Node::new(String::from("another_node")), 3);
+        assert_eq!(
+            ch.merge(&conflicting),
+            Err(MergeError::ReplicaConflict { name: String::from("test_node_0"), ours: 3, theirs: 4 })
+        );
+        assert!(!ch.replicas.contains_key("another_node"), "a failed merge changes nothing");
+
+        let narrow = ConsistentHash::builder().hasher(HashAlgorithm::Md5).ring_width(RingWidth::Bits32).build();
+        assert_eq!(ch.merge(&narrow), Err(MergeError::RingWidthMismatch));
+    }
+
+    #[test]
+    fn filter_keys_for_node() {
+        let mut ch = setup(nodes_fixture(3), 3, 1.25);
+        ch.set_hash_tag_extractor(hash_tag);
+        let keys: Vec<String> = (0..12).map(|i| format!("{{user{}}}:profile", i % 4)).collect();
+
+        let mut covered = 0;
+        for node in nodes_fixture(3) {
+            let on_node = ch.filter_keys_for_node(&keys, node.get_name());
+            for key in on_node.iter() {
+                assert_eq!(ch.get_node(key.to_string()), Some(node.clone()));
+            }
+            covered += on_node.len();
+        }
+        assert_eq!(covered, keys.len());
+        assert!(ch.filter_keys_for_node(&keys, "test_node_9").is_empty());
+    }
+
+    #[test]
+    fn export_and_import_load() {
+        let mut ch = setup(nodes_fixture(4), 1, 1.25);
+        for i in 0..10 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        let saved = ch.export_load();
+
+        let mut restarted = setup(nodes_fixture(4), 1, 1.25);
+        assert_eq!(restarted.import_load(saved.clone()), Ok(()));
+        assert_eq!(restarted.export_load(), saved);
+        assert_eq!(restarted.total_load, ch.total_load);
+        assert_eq!(restarted.audit(), Ok(()));
+
+        let mut bad = saved.clone();
+        bad.insert(String::from("test_node_9"), 3);
+        bad.insert(String::from("test_node_0"), 100);
+        assert_eq!(restarted.import_load(bad), Err(ImportLoadError::UnknownNode(String::from("test_node_9"))));
+        assert_eq!(restarted.export_load(), saved);
+
+        assert_eq!(restarted.import_load(HashMap::new()), Ok(()));
+        assert_eq!(restarted.total_load, 0);
+
+        // loads too large to add up saturate instead of overflowing, and audit says so
+        let huge: HashMap<String, u64> = ["test_node_0", "test_node_1"].iter().map(|name| (name.to_string(), u64::MAX)).collect();
+        assert_eq!(restarted.import_load(huge), Ok(()));
+        assert_eq!(restarted.total_load, u64::MAX);
+        assert_eq!(restarted.audit(), Err(vec![AuditError::LoadOverflow]));
+    }
+
+    #[test]
+    fn get_node_with_load() {
+        let mut ch = setup(nodes_fixture(3), 1, 10.0);
+        let node = ch.get_node(String::from("test_key1")).unwrap();
+        assert_eq!(ch.get_node_with_load(String::from("test_key1")), Some((node.clone(), 0)));
+        let _ = ch.assign_key(String::from("test_key1"));
+        assert_eq!(ch.get_node_with_load(String::from("test_key1")), Some((node, 1)));
+        assert_eq!(ConsistentHash::new().get_node_with_load(String::from("test_key1")), None);
+    }
+
+    #[test]
+    fn insertion_order_independence() {
+        let nodes = nodes_fixture(6);
+        let forward = setup(nodes.clone(), 5, 1.25);
+        let backward = setup(nodes.iter().rev().cloned().collect(), 5, 1.25);
+        assert!(forward.same_topology(&backward));
+        assert!(!forward.same_topology(&setup(nodes.clone(), 4, 1.25)));
+
+        // a 32-bit ring with enough positions is bound to have collisions, which the smaller
+        // name wins whichever node was added first
+        let build = |order: &[Node]| {
+            let mut ch = ConsistentHash::builder().hasher(HashAlgorithm::Md5).ring_width(RingWidth::Bits32).build();
+            for node in order.iter() {
+                ch.add_node(node, 1);
+            }
+            ch
+        };
+        let many = nodes_fixture(100_000);
+        let mut ch = build(&many);
+        let reversed: Vec<Node> = many.iter().rev().cloned().collect();
+        assert!(!ch.shared_positions.is_empty(), "expected a collision to test with");
+        assert!(ch.same_topology(&build(&reversed)));
+
+        // the loser of a collision takes the position back when the winner leaves
+        let (hash, claimants) = ch.shared_positions.iter().next().map(|(hash, claimants)| (*hash, claimants.clone())).unwrap();
+        assert_eq!(claimants.len(), 2);
+        let winner = ch.nodes[&hash].clone();
+        ch.remove_node(winner.get_name().clone());
+        assert!(claimants.contains(&ch.nodes[&hash]) && ch.nodes[&hash] != winner);
+    }
+
+    #[test]
+    fn iter_loads() {
+        let mut ch = setup(nodes_fixture(3), 1, 10.0);
+        for i in 0..6 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        let loads: HashMap<&str, u64> = ch.iter_loads().collect();
+        assert_eq!(loads.len(), 3);
+        assert_eq!(loads.values().sum::<u64>(), ch.total_load);
+        for (name, load) in loads {
+            assert_eq!(ch.load_per_node[name], load);
+        }
+    }
+
+    #[test]
+    fn distinct_zones() {
+        let mut ch = ConsistentHash::builder().hasher(HashAlgorithm::Md5).build();
+        for (i, zone) in ["a", "a", "a", "b", "b", "c"].iter().enumerate() {
+            ch.add_node(&Node::new(format!("test_node_{}", i)).with_tag(ZONE_TAG, zone), 4);
+        }
+        for i in 0..20 {
+            let key = format!("test_key{}", i);
+            let nodes = ch.get_n_nodes_distinct_zones(key.clone(), 3);
+            let mut zones: Vec<&str> = nodes.iter().map(|node| node.zone().unwrap()).collect();
+            zones.sort();
+            assert_eq!(zones, vec!["a", "b", "c"]);
+            assert_eq!(Some(nodes[0].clone()), ch.neighbors(key.clone()).0);
+            assert_eq!(ch.get_n_nodes_distinct_zones(key, 5).len(), 3);
+        }
+
+        // untagged nodes are each their own zone
+        ch.add_node(&Node::new(String::from("untagged_0")), 4);
+        ch.add_node(&Node::new(String::from("untagged_1")), 4);
+        assert_eq!(ch.get_n_nodes_distinct_zones(String::from("test_key1"), 10).len(), 5);
+        assert_eq!(Node::new(String::from("test_node_0")).with_tag("gpu", "a100").tag("gpu"), Some("a100"));
+    }
+
+    #[test]
+    fn owner_of_midpoint() {
+        let ch = setup(nodes_fixture(4), 5, 1.25);
+        let same = ch.owner_of_midpoint("test_key1", "test_key1");
+        assert_eq!(same, ch.neighbors(String::from("test_key1")).0);
+
+        let start = ch.key_hash("test_key1");
+        let end = ch.key_hash("test_key2");
+        let midpoint = start.wrapping_add(end.wrapping_sub(start) / 2);
+        let owner = ch.nodes.iter()
+            .find(|(hash, _)| **hash >= midpoint)
+            .or_else(|| ch.nodes.iter().next())
+            .map(|(_, node)| node.clone());
+        assert_eq!(ch.owner_of_midpoint("test_key1", "test_key2"), owner);
+        assert_eq!(ConsistentHash::new().owner_of_midpoint("test_key1", "test_key2"), None);
+    }
+
+    #[test]
+    fn add_node_at_positions() {
+        let mut ch = setup(nodes_fixture(3), 3, 1.25);
+        let pinned = Node::new(String::from("pinned_node"));
+        let positions = vec![vec![0x40; 16], vec![0x80; 16], vec![0xc0]];
+        ch.add_node_at_positions(&pinned, &positions);
+        assert_eq!(ch.size(), 3 * 3 + 3);
+        for position in [vec![0x40; 16], vec![0x80; 16], vec![0xc0]] {
+            assert_eq!(ch.nodes[&position_value(&position)], pinned);
+        }
+        assert_eq!(ch.audit(), Ok(()));
+
+        // positions survive replica changes and go with the node
+        assert!(!ch.set_replicas("pinned_node", 10));
+        ch.rebalance_replicas();
+        assert_eq!(ch.replicas["pinned_node"], 3);
+        ch.remove_node(String::from("pinned_node"));
+        assert_eq!(ch.size(), 3 * 3);
+        assert!(ch.pinned_positions.is_empty());
+        assert_eq!(ch.audit(), Ok(()));
+
+        // re-adding with add_node goes back to name-derived positions
+        ch.add_node_at_positions(&pinned, &positions);
+        ch.add_node(&pinned, 2);
+        assert!(!ch.nodes.contains_key(&position_value(&[0x40; 16])));
+        assert_eq!(ch.audit(), Ok(()));
+
+        // repeated positions, here also one that only repeats once cut, are held once
+        let repeated = vec![vec![0x40; 16], vec![0xc0], vec![0x40; 16], vec![0x40; 17]];
+        ch.add_node_at_positions(&pinned, &repeated);
+        assert_eq!(ch.virtual_count("pinned_node"), 2);
+        assert_eq!(ch.pinned_positions["pinned_node"].len(), 2);
+        assert_eq!(ch.replicas["pinned_node"], 2);
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn positions_order_like_their_bytes() {
+        for width in [RingWidth::Bits32, RingWidth::Bits128] {
+            let mut ch = ConsistentHash::builder().ring_width(width).build();
+            for node in nodes_fixture(20).iter() {
+                ch.add_node(node, 10);
+            }
+            let mut by_bytes: Vec<Vec<u8>> = ch.nodes.keys().map(|hash| ch.position_bytes(*hash)).collect();
+            by_bytes.sort();
+            let in_ring_order: Vec<Vec<u8>> = ch.sorted_positions().into_iter().map(|(hash, _)| hash).collect();
+            assert_eq!(in_ring_order, by_bytes);
+        }
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut lean = setup(nodes_fixture(2), 3, 1.25);
+        assert_eq!(lean.diagnostics(), Diagnostics::NONE);
+        let _ = lean.assign_key(String::from("test_key1"));
+        assert!(lean.last_assigned.is_empty());
+        assert!(!lean.unassign_key(String::from("test_key1")));
+        assert_eq!(lean.take_rejection_log(), None);
+
+        let mut ch = ConsistentHash::builder()
+            .hasher(HashAlgorithm::Md5)
+            .diagnostics(Diagnostics::KEY_TRACKING | Diagnostics::REJECTION_LOG)
+            .build();
+        assert!(ch.diagnostics().contains(Diagnostics::KEY_TRACKING));
+        assert!(!ch.diagnostics().contains(Diagnostics::TIMESTAMPS));
+        assert!(Diagnostics::ALL.contains(ch.diagnostics()));
+        assert!(ch.assign_key(String::from("test_key1")).is_err());
+        assert_eq!(ch.take_rejection_log(), Some(vec![String::from("test_key1")]));
+        assert_eq!(ch.take_rejection_log(), Some(Vec::new()));
+
+        ch.add_node(&Node::new(String::from("test_node_0")), 3);
+        let _ = ch.assign_key(String::from("test_key2"));
+        assert_eq!(ch.last_assigned("test_node_0"), None);
+        assert!(ch.unassign_key(String::from("test_key2")));
+    }
+
+    #[test]
+    fn replica_counts_are_position_counts() {
+        let mut ch = setup(nodes_fixture(1), 0, 1.0);
+        assert_eq!(ch.size(), 0);
+        assert_eq!(ch.get_node(String::from("test_key1")), None);
+
+        let node = Node::new(String::from("test_node_0"));
+        ch.add_node(&node, 1);
+        assert_eq!(ch.size(), 1);
+        ch.add_node(&node, 2);
+        assert_eq!(ch.size(), 2);
+        assert_eq!(ch.audit(), Ok(()));
+        // every replica, the first included, is hashed with its number
+        assert!(!ch.nodes.contains_key(&ch.digest(b"test_node_0")));
+    }
+
+    #[test]
+    fn get_node_async() {
+        use std::future::{ready, Ready};
+        use std::pin::pin;
+        use std::task::{Context, Poll, Waker};
+
+        // resolves any node whose name has a known address
+        struct Addresses(HashMap<String, String>);
+        impl NodeResolver for Addresses {
+            type Resolved = String;
+            type Future = Ready<Option<String>>;
+
+            fn resolve(&self, node: Node) -> Ready<Option<String>> {
+                ready(self.0.get(node.get_name()).cloned())
+            }
+        }
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let mut future = pin!(future);
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            }
+        }
+
+        let mut ch = setup(nodes_fixture(3), 3, 1.25);
+        let owner = ch.get_node(String::from("test_key1")).unwrap();
+        let addresses = Addresses(HashMap::from([(owner.get_name().clone(), String::from("10.0.0.1:6379"))]));
+        let pending = ch.get_node_async(String::from("test_key1"), &addresses);
+        // the node was picked before the ring changed
+        ch.remove_node(owner.get_name().clone());
+        assert_eq!(block_on(pending), Some(String::from("10.0.0.1:6379")));
+
+        assert_eq!(block_on(ch.get_node_async(String::from("test_key1"), &addresses)), None);
+        assert_eq!(block_on(ConsistentHash::new().get_node_async(String::from("test_key1"), &addresses)), None);
+    }
+
+    #[test]
+    fn add_node_for_fraction() {
+        let mut ch = setup(nodes_fixture(5), 50, 1.25);
+        let node = Node::new(String::from("fraction_node"));
+        let replicas = ch.add_node_for_fraction(&node, 0.1);
+        assert_eq!(ch.replicas["fraction_node"], replicas);
+        let owned = ch.ownership_fractions()["fraction_node"];
+        assert!((owned - 0.1).abs() < 0.01, "{} replicas own {}", replicas, owned);
+        assert_eq!(ch.audit(), Ok(()));
+
+        // re-adding ignores the node's own positions, so it lands on the same count
+        assert_eq!(ch.add_node_for_fraction(&node, 0.1), replicas);
+        assert!(ch.add_node_for_fraction(&node, 0.3) > replicas);
+        assert_eq!(ch.add_node_for_fraction(&node, 0.0), 0);
+        assert_eq!(ch.ownership_fractions()["fraction_node"], 0.0);
+
+        let mut empty = ConsistentHash::new();
+        assert_eq!(empty.add_node_for_fraction(&node, 0.5), 1);
+    }
+
+    #[test]
+    fn try_remove_node() {
+        let mut ch = setup(nodes_fixture(2), 3, 1.25);
+        let _ = ch.assign_key(String::from("test_key1"));
+        let owner = ch.get_node_with_policy(String::from("test_key1"), LoadPolicy::Ignore).unwrap();
+        assert_eq!(ch.try_remove_node("test_node_9"), Err(RemoveError::NotFound(String::from("test_node_9"))));
+
+        let removed = ch.try_remove_node(owner.get_name()).unwrap();
+        assert_eq!(removed, RemovedNode { node: owner.clone(), load: 1, replicas: 3 });
+        assert_eq!(ch.try_remove_node(owner.get_name()), Err(RemoveError::NotFound(owner.get_name().clone())));
+
+        // a node without replicas holds no positions but can still be removed
+        ch.add_node(&Node::new(String::from("test_node_9")), 0);
+        assert_eq!(ch.try_remove_node("test_node_9").unwrap().replicas, 0);
+
+        let mut empty = ConsistentHash::new();
+        assert_eq!(empty.try_remove_node("test_node_0"), Err(RemoveError::EmptyRing));
+    }
+
+    #[test]
+    fn first_and_last_positions() {
+        assert_eq!(ConsistentHash::new().first_position(), None);
+        assert_eq!(ConsistentHash::new().last_position(), None);
+
+        let ch = setup(nodes_fixture(4), 3, 1000.0);
+        let positions = ch.sorted_positions();
+        let first = ch.first_position().unwrap();
+        let last = ch.last_position().unwrap();
+        assert_eq!((first.0.clone(), first.1), positions[0]);
+        assert_eq!((last.0.clone(), last.1), positions[positions.len() - 1]);
+
+        // keys hashing past the last position wrap to the first one
+        let wrapping = (0..1000).map(|i| format!("test_key{}", i))
+            .find(|key| ch.position_bytes(ch.key_hash(key)) > last.0)
+            .unwrap();
+        assert_eq!(ch.get_node(wrapping).as_ref(), Some(first.1));
+    }
+
+    #[test]
+    fn shard_id() {
+        let ch = setup(nodes_fixture(3), 3, 1.25);
+        assert_eq!(ch.shard_id("test_key1", 0), 0);
+        assert_eq!(ch.shard_id("test_key1", 1), 0);
+
+        let mut counts = [0; 8];
+        for i in 0..8000 {
+            let key = format!("test_key{}", i);
+            let shard = ch.shard_id(&key, 8);
+            counts[shard as usize] += 1;
+            assert_eq!(ConsistentHash::with_hasher(HashAlgorithm::Md5).shard_id(&key, 8), shard, "shards ignore the nodes");
+            // a ninth shard only takes keys for itself
+            let grown = ch.shard_id(&key, 9);
+            assert!(grown == shard || grown == 8);
+        }
+        assert!(counts.iter().all(|count| *count > 800 && *count < 1200), "{:?}", counts);
+    }
+
+    #[test]
+    fn topology_log() {
+        assert_eq!(setup(nodes_fixture(2), 3, 1.25).topology_log(), None);
+
+        let logged = || ConsistentHash::builder().hasher(HashAlgorithm::Md5).diagnostics(Diagnostics::TOPOLOGY_LOG).build();
+        let mut ch = logged();
+        for node in nodes_fixture(4).iter() {
+            ch.add_node(node, 5);
+        }
+        ch.add_node_at_positions(&Node::new(String::from("pinned_node")), &[vec![0x80; 16]]);
+        assert!(ch.set_replicas("test_node_1", 8));
+        ch.remove_node(String::from("test_node_2"));
+        ch.remove_node(String::from("test_node_2"));
+        let adjustments = ch.rebalance_replicas();
+
+        let log = ch.topology_log().unwrap();
+        // rebalancing only logs the moves it keeps, at least one per adjusted node
+        assert!(log.len() >= 7 + adjustments.len());
+        assert!(log[7..].iter().all(|event| matches!(event.change, TopologyChange::SetReplicas { .. })));
+        assert!(log.iter().enumerate().all(|(i, event)| event.sequence == i as u64));
+        assert_eq!(log[5].change, TopologyChange::SetReplicas { name: String::from("test_node_1"), replicas: 8 });
+        assert_eq!(log[6].change, TopologyChange::RemoveNode { name: String::from("test_node_2") });
+
+        let mut replica = logged();
+        replica.replay_topology(log);
+        assert!(replica.same_topology(&ch));
+        assert_eq!(replica.topology_log(), ch.topology_log());
+    }
+
+    #[test]
+    fn estimate_distribution() {
+        let ch = setup(nodes_fixture(4), 20, 1.25);
+        let fractions = ch.ownership_fractions();
+        let estimate = ch.estimate_distribution(10_000_000);
+        assert_eq!(estimate.len(), 4);
+        assert_eq!(estimate.values().sum::<u64>(), 10_000_000);
+        for (name, keys) in estimate.iter() {
+            assert!((*keys as f64 - fractions[name] * 10_000_000.0).abs() <= 1.0);
+        }
+        assert_eq!(ch.estimate_distribution(3).values().sum::<u64>(), 3);
+        assert_eq!(ch.total_load, 0);
+
+        let mut idle = setup(nodes_fixture(2), 0, 1.25);
+        assert_eq!(idle.estimate_distribution(100).values().sum::<u64>(), 0);
+        idle.add_node(&Node::new(String::from("test_node_0")), 1);
+        assert_eq!(idle.estimate_distribution(100)["test_node_0"], 100);
+    }
+
+    #[test]
+    fn reweight_gradually() {
+        let mut ch = setup(nodes_fixture(4), 10, 1.25);
+        assert_eq!(ch.reweight_gradually("test_node_9", 20, 5), None);
+
+        let mut steps = Vec::new();
+        loop {
+            let before = ch.clone();
+            let step = ch.reweight_gradually("test_node_1", 22, 5).unwrap();
+            assert!((step.moved_fraction - before.moved_fraction(&ch)).abs() < 1e-9);
+            steps.push(step.replicas);
+            if step.done() {
+                break;
+            }
+        }
+        assert_eq!(steps, vec![15, 20, 22]);
+        assert_eq!(ch.replicas["test_node_1"], 22);
+
+        let step = ch.reweight_gradually("test_node_1", 22, 5).unwrap();
+        assert!(step.done());
+        assert_eq!(step.moved_fraction, 0.0);
+        assert_eq!(ch.reweight_gradually("test_node_1", 0, 0).unwrap().replicas, 21);
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn range_from() {
+        assert_eq!(ConsistentHash::new().range_from(&[0x80]).count(), 0);
+
+        let ch = setup(nodes_fixture(4), 5, 1.25);
+        let positions = ch.sorted_positions();
+        let start = ch.position_bytes(ch.key_hash("test_key1"));
+        let walk: Vec<(Vec<u8>, &Node)> = ch.range_from(&start).collect();
+        assert_eq!(walk.len(), positions.len());
+        assert_eq!(walk[0].1, &ch.get_node(String::from("test_key1")).unwrap());
+
+        // the walk is the sorted positions rotated to the first one at or after the start
+        let first = positions.partition_point(|(position, _)| *position < start);
+        let rotated: Vec<(Vec<u8>, &Node)> = positions[first..].iter().chain(positions[..first].iter()).cloned().collect();
+        assert_eq!(walk, rotated);
+        assert_eq!(ch.range_from(&positions[2].0).next().unwrap(), positions[2]);
+    }
+
+    #[test]
+    fn integer_keys() {
+        assert_eq!(ConsistentHash::new().get_node_u64(7), None);
+        let mut ch = setup(nodes_fixture(4), 10, 1.25);
+        ch.enable_key_tracking();
+        for key in 0..20u64 {
+            let node = ch.get_node_u64(key).unwrap();
+            let expected = ch.nodes.range(ch.digest(&key.to_be_bytes())..).chain(ch.nodes.iter()).next().unwrap().1;
+            assert_eq!(&node, expected);
+        }
+
+        let outcome = ch.assign_key_u64(7).unwrap();
+        assert_eq!(outcome.new_load, 1);
+        assert_eq!(ch.total_load, 1);
+        assert!(ch.assignments.as_ref().unwrap().is_empty(), "integer keys are not tracked");
+        assert_eq!(ConsistentHash::new().assign_key_u64(7), Err(RingError::EmptyRing));
+    }
+
+    #[test]
+    fn warmup_cap() {
+        let accepted = |ch: &mut ConsistentHash| {
+            (0..1000).take_while(|i| ch.assign_key(format!("test_key{}", i)).is_ok()).count()
+        };
+
+        // a per-position average is what makes a fresh ring saturate this early
+        let mut fresh = setup_with_basis(nodes_fixture(3), 10, 1.25, AverageBasis::Virtual);
+        assert_eq!(accepted(&mut fresh), 3, "one key per node before the first rejection");
+
+        let mut warm = setup_with_basis(nodes_fixture(3), 10, 1.25, AverageBasis::Virtual);
+        warm.set_warmup_cap(50);
+        assert_eq!(accepted(&mut warm), 150);
+        assert_eq!(warm.headroom().values().sum::<i64>(), 0);
+
+        // the floor scales with capacity weight, so a draining node stays drained
+        let mut draining = setup_with_basis(nodes_fixture(3), 10, 1.25, AverageBasis::Virtual);
+        draining.set_warmup_cap(50);
+        draining.set_capacity_weight("test_node_0", 0.0);
+        assert_eq!(accepted(&mut draining), 100);
+        assert_eq!(draining.load_per_node["test_node_0"], 0);
+    }
+
+    #[test]
+    fn verify_placement() {
+        let mut ch = setup(nodes_fixture(3), 5, 1.25);
+        let node = Node::new(String::from("test_node_9"));
+        let report = ch.verify_placement(&node, 4);
+        assert_eq!(report, PlacementReport { replicas: 4, distinct: 4, held: 4, collisions: Vec::new() });
+        assert!(report.is_complete());
+
+        // squat on two of its positions, once with a name that wins the position and once not
+        let wanted = ch.replica_hashes("test_node_9", 4);
+        let winner = Node::new(String::from("a_node"));
+        let loser = Node::new(String::from("z_node"));
+        ch.add_node_at_positions(&winner, &[ch.position_bytes(wanted[1])]);
+        ch.add_node_at_positions(&loser, &[ch.position_bytes(wanted[2])]);
+        let size = ch.size();
+
+        let report = ch.verify_placement(&node, 4);
+        assert_eq!(report.held, 3);
+        assert!(!report.is_complete());
+        assert_eq!(report.collisions.len(), 2);
+        assert!(report.collisions.contains(&(ch.position_bytes(wanted[1]), winner)));
+        assert_eq!(ch.size(), size, "verifying must not touch the ring");
+
+        ch.add_node(&node, 4);
+        assert_eq!(ch.nodes.values().filter(|holder| *holder == &node).count(), report.held);
+    }
+
+    #[test]
+    fn transaction() {
+        let mut ch = setup(nodes_fixture(3), 5, 1.25);
+        let before = ch.clone();
+        assert!(!ch.transaction(|txn| {
+            txn.remove_node("test_node_0");
+            txn.abort();
+        }));
+        assert!(ch.same_topology(&before));
+
+        let mut expected = before.clone();
+        expected.remove_node(String::from("test_node_0"));
+        expected.add_node(&Node::new(String::from("test_node_7")), 4);
+        expected.set_replicas("test_node_1", 8);
+        assert!(ch.transaction(|txn| {
+            txn.remove_node("test_node_0");
+            txn.add_node(&Node::new(String::from("test_node_7")), 4);
+            txn.set_replicas("test_node_1", 8);
+            assert_eq!(txn.changes().len(), 3);
+        }));
+        assert!(ch.same_topology(&expected));
+
+        // a panicking transaction applies nothing
+        let mut guarded = before.clone();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            guarded.transaction(|txn| {
+                txn.remove_node("test_node_0");
+                panic!("orchestrator bug");
+            })
+        }));
+        assert!(result.is_err());
+        assert!(guarded.same_topology(&before));
+    }
+
+    #[test]
+    fn virtual_count() {
+        let mut ch = setup(nodes_fixture(3), 5, 1.25);
+        assert_eq!(ch.virtual_count("test_node_1"), 5);
+        assert_eq!(ch.virtual_count("test_node_9"), 0);
+
+        // a squatter with a smaller name takes one of the node's positions
+        let taken = ch.node_hashes("test_node_1", 5)[3];
+        ch.add_node_at_positions(&Node::new(String::from("a_node")), &[ch.position_bytes(taken)]);
+        assert_eq!(ch.virtual_count("test_node_1"), 4);
+        assert_eq!(ch.replicas["test_node_1"], 5);
+        ch.remove_node(String::from("a_node"));
+        assert_eq!(ch.virtual_count("test_node_1"), 5);
+    }
+
+    #[test]
+    fn assign_key_with_cost() {
+        let mut ch = setup(nodes_fixture(3), 1, 1.5);
+        ch.enable_key_tracking();
+        let cost = |key: &str| if key.starts_with("heavy") { 6 } else { 1 };
+        for i in 0..30 {
+            let _ = ch.assign_key_with_cost(format!("light_key{}", i), cost);
+        }
+        assert_eq!(ch.total_load, 30);
+
+        // checked against the whole cost, so it lands where 6 more fits under the cap
+        let fits: Vec<String> = ch.replicas.keys().filter(|name| ch.check_load(name, 6)).cloned().collect();
+        let outcome = ch.assign_key_with_cost(String::from("heavy_key1"), cost).unwrap();
+        assert!(fits.contains(outcome.node.get_name()));
+        assert_eq!(outcome.new_load, ch.load_per_node[outcome.node.get_name()]);
+        assert_eq!(ch.total_load, 36);
+
+        assert!(ch.unassign_key(String::from("heavy_key1")));
+        assert_eq!(ch.total_load, 30);
+        assert_eq!(ch.assign_key_with_cost(String::from("heavy_key2"), |_key| 1000), Err(RingError::Saturated));
+        assert_eq!(ch.assign_key_with_cost(String::from("heavy_key3"), |_key| u64::MAX), Err(RingError::Saturated));
+        assert_eq!(ch.audit(), Ok(()));
+    }
+
+    #[test]
+    fn saturation_policy() {
+        // equal loads at a load factor of 1 leave every node at its cap
+        let mut ch = setup(nodes_fixture(3), 1, 1.0);
+        for i in 0..3 {
+            let _ = ch.assign_key(format!("test_key{}", i));
+        }
+        assert_eq!(ch.try_get_node(String::from("test_key9")), Err(RingError::Saturated));
+
+        ch.set_saturation_policy(SaturationPolicy::LeastLoaded);
+        assert_eq!(ch.get_node(String::from("test_key9")).unwrap().get_name(), "test_node_0", "ties go by name");
+        ch.set_node_state("test_node_0", NodeState::Unhealthy);
+        let outcome = ch.assign_key(String::from("test_key9")).unwrap();
+        assert_eq!(outcome.node.get_name(), "test_node_1");
+        assert_eq!(outcome.new_load, 2, "over the cap of 1 it was placed under");
+    }
+
+    #[test]
+    fn prometheus_metrics() {
+        let mut ch = setup(nodes_fixture(2), 4, 1.0);
+        ch.add_node(&Node::new(String::from("rack \"b\"\\1")), 0);
+        let assigned = (0..6).filter(|i| ch.assign_key(format!("test_key{}", i)).is_ok()).count();
+        assert!(assigned > 0 && assigned < 6);
+
+        let text = ch.prometheus_metrics();
+        assert!(text.starts_with("# HELP consistenthash_node_load"));
+        assert!(text.contains(&format!("consistenthash_node_load{{node=\"test_node_0\"}} {}\n", ch.load_per_node["test_node_0"])));
+        assert!(text.contains("consistenthash_node_load{node=\"rack \\\"b\\\"\\\\1\"} 0\n"));
+        for line in [
+            format!("consistenthash_total_load {}\n", assigned),
+            String::from("consistenthash_physical_nodes 3\n"),
+            String::from("consistenthash_virtual_nodes 8\n"),
+            // averaged over the two nodes holding positions, like the cap
+            format!("consistenthash_average_load {}\n", assigned as f64 / 2.0),
+            String::from("# TYPE consistenthash_rejections_total counter\n"),
+            format!("consistenthash_rejections_total {}\n", 6 - assigned),
+        ] {
+            assert!(text.contains(&line), "missing {:?} in\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn average_basis() {
+        let caps = |ch: &ConsistentHash| -> Vec<u64> {
+            let mut caps: Vec<u64> =
+                ch.headroom().iter().map(|(name, &headroom)| (ch.load_per_node[name] as i64 + headroom) as u64).collect();
+            caps.sort();
+            caps
+        };
+
+        let mut physical = setup_with_basis(nodes_fixture(3), 10, 1.25, AverageBasis::Physical);
+        for i in 0..300 {
+            assert!(physical.assign_key(format!("test_key{}", i)).is_ok());
+        }
+        // 1.25 times the 100 keys each node would hold on a perfectly even ring
+        assert_eq!(caps(&physical), vec![125, 125, 125]);
+
+        let mut per_position = setup_with_basis(nodes_fixture(3), 10, 1.25, AverageBasis::Virtual);
+        let accepted = (0..300).take_while(|i| per_position.assign_key(format!("test_key{}", i)).is_ok()).count();
+        assert!(accepted < 300);
+        let cap = (accepted as f64 / 30.0 * 1.25).ceil() as u64;
+        assert_eq!(caps(&per_position), vec![cap, cap, cap]);
+        assert_eq!(per_position.headroom().values().sum::<i64>(), 0);
+
+        assert_eq!(ConsistentHash::new().average_basis, AverageBasis::Physical);
+    }
+
+    #[test]
+    fn local_density() {
+        let mut ch = setup(nodes_fixture(2), 40, 1.0);
+        let quarter = |i: u8| vec![i * 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        ch.add_node_at_positions(&Node::new(String::from("spread")), &(0..4).map(quarter).collect::<Vec<_>>());
+        ch.add_node_at_positions(&Node::new(String::from("bunched")), &(0..8).map(|i| vec![10, i]).collect::<Vec<_>>());
+
+        assert!(ch.local_density("spread").abs() < 1e-9);
+        let hashed = ch.local_density("test_node_0");
+        assert!(hashed > 0.5 && hashed < 1.5, "hashed positions scored {}", hashed);
+        assert!(ch.local_density("bunched") > 2.5);
+
+        ch.add_node(&Node::new(String::from("single")), 1);
+        assert_eq!(ch.local_density("single"), 0.0);
+        assert_eq!(ch.local_density("missing"), 0.0);
+    }
+
+    #[test]
+    fn failover_depth() {
+        let mut ch = setup(nodes_fixture(4), 5, 1.0);
+        assert_eq!(ch.failover_depth(String::from("test_key1")), 4);
+
+        ch.set_node_state("test_node_0", NodeState::Unhealthy);
+        ch.set_node_state("test_node_1", NodeState::Degraded);
+        assert_eq!(ch.failover_depth(String::from("test_key1")), 3);
+
+        // filling the first node every key reaches leaves the rest
+        let owner = ch.assign_key(String::from("test_key1")).unwrap().node;
+        assert!(!ch.check_load(owner.get_name(), 1));
+        assert_eq!(ch.failover_depth(String::from("test_key1")), 2);
+
+        ch.set_scan_limit(1);
+        let depths: Vec<usize> = (0..20).map(|i| ch.failover_depth(format!("test_key{}", i))).collect();
+        assert!(depths.iter().all(|&depth| depth <= 1));
+        assert!(depths.contains(&0) && depths.contains(&1));
+
+        assert_eq!(ConsistentHash::new().failover_depth(String::from("test_key1")), 0);
+    }
+
+    #[test]
+    fn placement_version() {
+        let ring = |version: PlacementVersion| {
+            let mut ch = ConsistentHash::builder().hasher(HashAlgorithm::Md5).placement_version(version).build();
+            ch.add_node(&Node::new(String::from("test_node_0")), 12);
+            ch
+        };
+        let v1 = ring(PlacementVersion::V1);
+        let v2 = ring(PlacementVersion::V2);
+        assert_eq!(ConsistentHash::new().placement_version, PlacementVersion::V1);
+        assert_eq!(v1.nodes, setup(nodes_fixture(1), 12, 1.0).nodes, "V1 keeps existing placement");
+
+        let mut identifier = b"test_node_0".to_vec();
+        identifier.push(REPLICA_SEPARATOR);
+        identifier.extend_from_slice(&7u32.to_be_bytes());
+        assert!(v2.nodes.contains_key(&position_value(&md5::compute(&identifier).0)));
+        assert!(v1.nodes.keys().all(|hash| !v2.nodes.contains_key(hash)));
+
+        assert_eq!(ring(PlacementVersion::V1).merge(&v2), Err(MergeError::PlacementVersionMismatch));
+    }
+
+    #[test]
+    fn memory_footprint() {
+        let mut ch = setup(nodes_fixture(2), 10, 1.0);
+        let small = ch.memory_footprint();
+        assert!(small > 20 * mem::size_of::<u128>());
+
+        ch.set_replicas("test_node_0", 1000);
+        let per_position = mem::size_of::<u128>() + mem::size_of::<Node>() + "test_node_0".len();
+        assert!(ch.memory_footprint() >= small + 990 * per_position);
+
+        ch.add_node(&Node::new(String::from("test_node_9")).with_tag(ZONE_TAG, "zone_a"), 0);
+        assert!(ch.memory_footprint() > small + 990 * per_position + 2 * "test_node_9".len());
+        assert_eq!(ConsistentHash::new().memory_footprint(), 0);
+    }
+
+    #[test]
+    fn get_node_at_version() {
+        let mut ch = ConsistentHash::builder().hasher(HashAlgorithm::Md5).diagnostics(Diagnostics::TOPOLOGY_LOG).build();
+        for node in nodes_fixture(3).iter() {
+            ch.add_node(node, 4);
+        }
+        let before: Vec<Option<Node>> = (0..50).map(|i| ch.get_node(format!("test_key{}", i))).collect();
+        ch.remove_node(String::from("test_node_1"));
+        ch.add_node(&Node::new(String::from("test_node_9")), 4);
+
+        let version = ch.topology_log().unwrap().len() as u64;
+        for (i, owner) in before.iter().enumerate() {
+            let key = format!("test_key{}", i);
+            assert_eq!(&ch.get_node_at_version(key.clone(), 3), owner);
+            assert_eq!(ch.get_node_at_version(key.clone(), version), ch.get_node(key));
+        }
+        assert_eq!(ch.get_node_at_version(String::from("test_key1"), 0), None);
+        assert_eq!(ch.get_node_at_version(String::from("test_key1"), version + 1), None);
+        assert_eq!(setup(nodes_fixture(1), 1, 1.0).get_node_at_version(String::from("test_key1"), 1), None);
+
+        // empty keys are refused at every version of a strict ring, like live lookups
+        assert!(ch.get_node_at_version(String::new(), version).is_some());
+        let mut strict = ConsistentHash::builder().hasher(HashAlgorithm::Md5).diagnostics(Diagnostics::TOPOLOGY_LOG).strict(true).build();
+        strict.add_node(&Node::new(String::from("test_node_0")), 4);
+        assert_eq!(strict.get_node(String::new()), None);
+        assert_eq!(strict.get_node_at_version(String::new(), 1), None);
+        assert!(strict.get_node_at_version(String::from("test_key1"), 1).is_some());
+    }
+
+    #[test]
+    fn assign_key_returning_delta() {
+        let mut ch = setup(nodes_fixture(2), 1, 3.0);
+        let (node, before, after) = ch.assign_key_returning_delta(String::from("test_key1")).unwrap();
+        assert_eq!((before, after), (0, 1));
+        let (again, before, after) = ch.assign_key_returning_delta(String::from("test_key1")).unwrap();
+        assert_eq!(again, node);
+        assert_eq!((before, after), (1, 2));
+        assert_eq!(ch.load_per_node[node.get_name()], 2);
+
+        assert_eq!(ConsistentHash::new().assign_key_returning_delta(String::from("test_key1")), Err(RingError::EmptyRing));
+    }
+
+    #[test]
+    fn get_node_attempt() {
+        let ch = setup(nodes_fixture(3), 4, 1.0);
+        let key = String::from("test_key1");
+        let placement = ch.get_placement(key.clone(), 3).unwrap();
+        assert_eq!(ch.get_node_attempt(key.clone(), 0), Some(placement.primary.clone()));
+        assert_eq!(ch.get_node_attempt(key.clone(), 1), Some(placement.replicas[0].clone()));
+        assert_eq!(ch.get_node_attempt(key.clone(), 2), Some(placement.replicas[1].clone()));
+        assert_eq!(ch.get_node_attempt(key.clone(), 3), Some(placement.primary.clone()));
+        assert_eq!(ch.get_node_attempt(key.clone(), 7), Some(placement.replicas[0].clone()));
+
+        assert_eq!(ConsistentHash::new().get_node_attempt(key, 0), None);
+    }
+
+    #[test]
+    fn simulate_load_factor() {
+        let mut ch = setup(nodes_fixture(3), 10, 1.25);
+        let _ = ch.assign_key(String::from("test_key0"));
+        let keys: Vec<String> = (1..300).map(|i| format!("test_key{}", i)).collect();
+
+        let comparison = ch.simulate_load_factor(1.25, &keys);
+        assert_eq!(comparison.current, comparison.proposed);
+        assert_eq!(comparison.current.rejected, 0);
+        assert!(comparison.current.max_load >= 100 && comparison.current.max_load <= 125);
+
+        // a cap at the average fills up as soon as every node carries the same load
+        let tighter = ch.simulate_load_factor(1.0, &keys);
+        assert_eq!(tighter.current, comparison.current);
+        assert_eq!(tighter.proposed.load_factor, 1.0);
+        assert!(tighter.proposed.rejected > 0);
+        assert_eq!(ch.total_load, 1, "the ring itself is untouched");
+
+        ch.set_load_factor(1.0);
+        assert_eq!(ch.simulate_load_factor(1.25, &keys).current, tighter.proposed);
+    }
+
+    #[test]
+    fn get_node_filtered() {
+        let mut ch = setup(nodes_fixture(3), 4, 1.0);
+        for i in 0..2 {
+            let gpu = Node::new(format!("gpu_node_{}", i)).with_tag("gpu", "true").with_tag(ZONE_TAG, &format!("zone_{}", i));
+            ch.add_node(&gpu, 4);
+        }
+
+        for i in 0..50 {
+            let key = format!("test_key{}", i);
+            assert_eq!(ch.get_node_filtered(key.clone(), &[]), ch.get_node_with_policy(key.clone(), LoadPolicy::Ignore));
+            let gpu = ch.get_node_filtered(key.clone(), &[("gpu", "true")]).unwrap();
+            assert!(gpu.get_name().starts_with("gpu_node_"));
+            let zoned = ch.get_node_filtered(key, &[("gpu", "true"), (ZONE_TAG, "zone_1")]).unwrap();
+            assert_eq!(zoned.get_name(), "gpu_node_1");
+        }
+
+        ch.set_node_state("gpu_node_1", NodeState::Unhealthy);
+        assert_eq!(ch.get_node_filtered(String::from("test_key1"), &[(ZONE_TAG, "zone_1")]), None);
+        assert_eq!(ch.get_node_filtered(String::from("test_key1"), &[("gpu", "false")]), None);
+    }
+
+    #[test]
+    fn takeover() {
+        let mut ch = ConsistentHash::builder()
+            .hasher(HashAlgorithm::Md5)
+            .load_factor(3.0)
+            .diagnostics(Diagnostics::KEY_TRACKING)
+            .build();
+        for node in nodes_fixture(4).iter() {
+            ch.add_node(node, 8);
+        }
+        for i in 0..20 {
+            ch.assign_key(format!("test_key{}", i)).unwrap();
+        }
+        let before: Vec<Node> = (0..200).map(|i| ch.get_node_with_policy(format!("test_key{}", i), LoadPolicy::Ignore).unwrap()).collect();
+        let failed_load = ch.load_per_node["test_node_2"];
+
+        let spare = Node::new(String::from("spare_node"));
+        assert!(ch.takeover("test_node_2", &spare));
+        for (i, owner) in before.iter().enumerate() {
+            let now = ch.get_node_with_policy(format!("test_key{}", i), LoadPolicy::Ignore).unwrap();
+            if owner.get_name() == "test_node_2" {
+                assert_eq!(now, spare);
+            } else {
+                assert_eq!(&now, owner);
+            }
+        }
+        assert_eq!(ch.virtual_count("spare_node"), 8);
+        assert_eq!(ch.load_per_node["spare_node"], failed_load);
+        assert_eq!(ch.total_load, 20);
+        let moved = ch.assignments.as_ref().unwrap().iter()
+            .find(|(_key, (assigned, _cost))| assigned == "spare_node")
+            .map(|(key, _)| key.clone())
+            .unwrap();
+        assert!(ch.unassign_key(moved));
+        assert_eq!(ch.load_per_node["spare_node"], failed_load - 1);
+
+        assert!(!ch.takeover("test_node_2", &Node::new(String::from("other_spare"))));
+        assert!(!ch.takeover("test_node_0", &spare));
+        assert_eq!(ch.size(), 32);
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut ch = setup(nodes_fixture(2), 3, 1.0);
+        let events = ch.subscribe();
+        let dropped = ch.subscribe();
+        drop(dropped);
+
+        ch.add_node(&Node::new(String::from("test_node_9")), 3);
+        ch.remove_node(String::from("test_node_0"));
+        let received: Vec<TopologyEvent> = events.try_iter().collect();
+        assert_eq!(received, vec![
+            TopologyEvent {
+                sequence: 0,
+                change: TopologyChange::AddNode { node: Node::new(String::from("test_node_9")), replicas: 3 },
+            },
+            TopologyEvent { sequence: 1, change: TopologyChange::RemoveNode { name: String::from("test_node_0") } },
+        ]);
+        assert_eq!(ch.subscribers.len(), 1);
+        assert_eq!(ch.topology_log(), None);
+
+        // planning and simulating change a copy of the ring, which must not reach subscribers
+        let target = vec![(String::from("test_node_1"), 6), (String::from("test_node_8"), 3)];
+        assert_eq!(ch.plan_scale(&target).steps.len(), 3);
+        ch.simulate((0..10).map(|i| format!("test_key{}", i)));
+        assert!(ch.worst_case_absorber("test_node_1").is_some());
+        assert_eq!(events.try_iter().count(), 0);
+
+        drop(events);
+        ch.remove_node(String::from("test_node_1"));
+        assert!(ch.subscribers.is_empty());
+    }
+
+    #[test]
+    fn get_node_salted() {
+        let ch = setup(nodes_fixture(5), 8, 1.0);
+        let placement = |salt: &[u8]| -> Vec<Node> {
+            (0..100).map(|i| ch.get_node_salted(format!("test_key{}", i), salt).unwrap()).collect()
+        };
+        assert_eq!(placement(b"experiment"), placement(b"experiment"));
+        assert_ne!(placement(b"experiment"), placement(b"control"));
+        let unsalted: Vec<Node> = (0..100).map(|i| ch.get_node(format!("test_key{}", i)).unwrap()).collect();
+        assert_ne!(placement(b""), unsalted);
+
+        // "ab" + "c" and "a" + "bc" concatenate alike but hash apart
+        let mut input = 2u64.to_be_bytes().to_vec();
+        input.extend_from_slice(b"abc");
+        assert_eq!(ch.get_node_salted(String::from("c"), b"ab"), ch.nearest_node_under_load(ch.digest(&input), 1, LoadPolicy::Strict).ok().cloned());
+        let mut other = 1u64.to_be_bytes().to_vec();
+        other.extend_from_slice(b"abc");
+        assert_ne!(ch.digest(&input), ch.digest(&other));
+
+        assert_eq!(ConsistentHash::new().get_node_salted(String::from("test_key1"), b"experiment"), None);
+    }
+
+    #[test]
+    fn ownership_changes() {
+        let before = setup(nodes_fixture(4), 8, 1.0);
+        let mut after = before.clone();
+        after.remove_node(String::from("test_node_3"));
+        after.add_node(&Node::new(String::from("test_node_9")), 8);
+        let keys: Vec<String> = (0..200).map(|i| format!("test_key{}", i)).collect();
+
+        let changes = ConsistentHash::ownership_changes(&before, &after, &keys);
+        assert!(!changes.is_empty());
+        for (key, old, new) in changes.iter() {
+            assert_ne!(old, new);
+            assert_eq!(before.get_node_with_policy(key.clone(), LoadPolicy::Ignore).as_ref(), Some(old));
+            assert_eq!(after.get_node_with_policy(key.clone(), LoadPolicy::Ignore).as_ref(), Some(new));
+            // only keys leaving the removed node or joining the new one move
+            assert!(old.get_name() == "test_node_3" || new.get_name() == "test_node_9");
+        }
+        let leaving = keys.iter()
+            .filter(|key| before.get_node_with_policy(key.to_string(), LoadPolicy::Ignore).unwrap().get_name() == "test_node_3")
+            .count();
+        assert!(changes.len() >= leaving);
+
+        assert!(ConsistentHash::ownership_changes(&before, &before, &keys).is_empty());
+        assert!(ConsistentHash::ownership_changes(&before, &ConsistentHash::new(), &keys).is_empty());
+    }
+
+    #[test]
+    fn get_node_with_min_headroom() {
+        let mut ch = setup(nodes_fixture(3), 1, 2.0);
+        ch.set_warmup_cap(4);
+        let key = String::from("test_key1");
+        let owner = ch.get_node(key.clone()).unwrap();
+        assert_eq!(ch.get_node_with_min_headroom(key.clone(), 4), Some(owner.clone()));
+
+        ch.assign_key_with_cost(key.clone(), |_| 3).unwrap();
+        let headroom = ch.headroom();
+        assert_eq!(headroom[owner.get_name()], 1);
+        assert_eq!(ch.get_node_with_min_headroom(key.clone(), 1), Some(owner.clone()));
+        let roomier = ch.get_node_with_min_headroom(key.clone(), 2).unwrap();
+        assert_ne!(roomier, owner);
+        assert!(headroom[roomier.get_name()] >= 2);
+
+        ch.set_saturation_policy(SaturationPolicy::LeastLoaded);
+        assert_eq!(ch.get_node_with_min_headroom(key, 50), None);
+    }
+
+    #[test]
+    fn assign_key_is_idempotent_when_tracked() {
+        let mut ch = ConsistentHash::builder()
+            .hasher(HashAlgorithm::Md5)
+            .load_factor(3.0)
+            .diagnostics(Diagnostics::KEY_TRACKING)
+            .build();
+        for node in nodes_fixture(3).iter() {
+            ch.add_node(node, 2);
+        }
+        let first = ch.assign_key(String::from("test_key1")).unwrap();
+        assert_eq!(ch.assign_key(String::from("test_key1")).unwrap(), first);
+        assert_eq!(ch.assign_key_with_cost(String::from("test_key1"), |_| 5).unwrap(), first);
+        assert_eq!(ch.assign_key_returning_delta(String::from("test_key1")).unwrap(), (first.node.clone(), 1, 1));
+        assert_eq!(ch.total_load, 1);
+
+        assert!(ch.unassign_key(String::from("test_key1")));
+        assert_eq!(ch.total_load, 0);
+        assert_eq!(ch.assign_key(String::from("test_key1")).unwrap().new_load, 1);
+
+        // untracked rings count every call
+        let mut untracked = setup(nodes_fixture(3), 2, 3.0);
+        let _ = untracked.assign_key(String::from("test_key1"));
+        let _ = untracked.assign_key(String::from("test_key1"));
+        assert_eq!(untracked.total_load, 2);
+    }
+
+    #[test]
+    fn keys_by_node() {
+        assert_eq!(setup(nodes_fixture(2), 2, 1.0).keys_by_node(), None);
+        assert_eq!(setup(nodes_fixture(2), 2, 1.0).keys_by_assignment(), None);
+
+        let mut ch = ConsistentHash::builder()
+            .hasher(HashAlgorithm::Md5)
+            .load_factor(3.0)
+            .diagnostics(Diagnostics::KEY_TRACKING)
+            .build();
+        for node in nodes_fixture(3).iter() {
+            ch.add_node(node, 4);
+        }
+        for i in 0..30 {
+            ch.assign_key(format!("test_key{}", i)).unwrap();
+        }
+        let grouped = ch.keys_by_assignment().unwrap();
+        assert_eq!(grouped.values().map(|keys| keys.len()).sum::<usize>(), 30);
+        for (name, keys) in grouped.iter() {
+            assert_eq!(keys.len() as u64, ch.load_per_node[name]);
+            assert!(keys.windows(2).all(|pair| ch.key_hash(&pair[0]) <= ch.key_hash(&pair[1])));
+        }
+        for (name, keys) in ch.keys_by_node().unwrap().iter() {
+            assert!(keys.iter().all(|key| ch.get_node(key.clone()).unwrap().get_name() == name));
+            assert!(keys.windows(2).all(|pair| ch.key_hash(&pair[0]) <= ch.key_hash(&pair[1])));
+        }
+
+        let drained = grouped["test_node_1"].clone();
+        assert!(ch.unassign_key(drained[0].clone()));
+        assert_eq!(ch.keys_by_assignment().unwrap()["test_node_1"], drained[1..]);
+        ch.remove_node(String::from("test_node_1"));
+        assert!(!ch.keys_by_assignment().unwrap().contains_key("test_node_1"));
+
+        // once its node is full, a key assigned there routes on to the next node with room
+        let mut tight = setup(nodes_fixture(3), 1, 1.0);
+        tight.enable_key_tracking();
+        let _ = tight.assign_key(String::from("test_key1"));
+        let full = tight.keys_by_assignment().unwrap().into_keys().next().unwrap();
+        let routed = tight.keys_by_node().unwrap();
+        assert!(!routed.contains_key(&full));
+        assert_eq!(routed.values().flatten().collect::<Vec<_>>(), vec!["test_key1"]);
+    }
+
+    #[test]
+    fn hot_keys() {
+        let mut ch = setup(nodes_fixture(5), 4, 1.0);
+        let key = String::from("test_key1");
+        assert_eq!(ch.hot_key_nodes(key.clone()), vec![ch.get_node(key.clone()).unwrap()]);
+
+        ch.register_hot_key(key.clone(), 3);
+        let copies = ch.hot_key_nodes(key.clone());
+        assert_eq!(copies.len(), 3);
+        let reads: Vec<Node> = (0..9).map(|request| ch.get_node_for_read(key.clone(), request).unwrap()).collect();
+        for (request, node) in reads.iter().enumerate() {
+            assert_eq!(node, &copies[request % 3]);
+        }
+        assert_eq!(ch.get_node_for_read(String::from("test_key2"), 4), ch.get_node(String::from("test_key2")));
+
+        ch.register_hot_key(key.clone(), 1);
+        assert_eq!(ch.get_node_for_read(key.clone(), 2), ch.get_node(key));
+    }
+
+    #[test]
+    fn worst_case_absorber() {
+        let mut ch = setup(nodes_fixture(3), 1, 1.0);
+        let (absorber, gained) = ch.worst_case_absorber("test_node_0").unwrap();
+        let owned = ch.ownership_fractions();
+        // with one position each, the failed node's whole arc goes to the next node clockwise
+        assert!((gained - owned["test_node_0"]).abs() < 1e-9);
+        assert_ne!(absorber.get_name(), "test_node_0");
+
+        for i in 0..10 {
+            ch.add_node(&Node::new(format!("spread_node_{}", i)), 40);
+        }
+        ch.set_replicas("test_node_0", 40);
+        let (_, gained) = ch.worst_case_absorber("spread_node_3").unwrap();
+        let lost = ch.ownership_fractions()["spread_node_3"];
+        assert!(gained > 0.0 && gained < lost / 2.0, "one node absorbed {} of {}", gained, lost);
+
+        assert_eq!(ch.worst_case_absorber("missing"), None);
+        assert_eq!(setup(nodes_fixture(1), 3, 1.0).worst_case_absorber("test_node_0"), None);
+    }
+
+    #[test]
+    fn tags_survive_zero_replicas() {
+        let mut ch = setup(nodes_fixture(2), 3, 1.25);
+        let tagged = Node::new(String::from("test_node_9")).with_tag(ZONE_TAG, "zone_a");
+        ch.add_node(&tagged, 0);
+        assert!(ch.set_replicas("test_node_9", 3));
+        assert!(ch.list_nodes_deduped().unwrap().contains(&tagged));
+
+        assert!(ch.set_replicas("test_node_9", 0));
+        assert!(ch.set_replicas("test_node_9", 2));
+        assert_eq!(ch.sorted_positions().iter().filter(|(_position, node)| **node == tagged).count(), 2);
+        assert!(ch.nodes_by_capacity().iter().any(|(node, _headroom)| *node == tagged));
+
+        ch.set_replicas("test_node_9", 0);
+        assert_eq!(ch.remove_node(String::from("test_node_9")), Some((tagged, 0)));
+    }
 }
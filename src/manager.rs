@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{ConsistentHash, Node};
+
+/// Independent rings kept under one roof, e.g. one per data type or tenant, that often share the
+/// same fleet of nodes.
+///
+/// Each ring keeps its own replica counts, loads and settings and can be reached by name to
+/// change on its own. `add_node_to_all` and `remove_node_from_all` change every ring at once for
+/// the common case where they should follow the same topology. The manager also remembers each
+/// node added through it, so its tags and other metadata can be looked up in one place instead of
+/// in whichever ring happens to hold it.
+#[derive(Clone, Default)]
+pub struct RingManager {
+    rings: BTreeMap<String, ConsistentHash>,
+    nodes: HashMap<String, Node>,
+}
+
+impl RingManager {
+    pub fn new() -> RingManager {
+        RingManager::default()
+    }
+
+    /// Adds a ring under `name`, returning the ring it replaces if there was one. The ring keeps
+    /// whatever nodes it already has; nodes added to the others earlier are not added to it.
+    pub fn insert_ring(&mut self, name: &str, ring: ConsistentHash) -> Option<ConsistentHash> {
+        self.rings.insert(name.to_string(), ring)
+    }
+
+    pub fn remove_ring(&mut self, name: &str) -> Option<ConsistentHash> {
+        self.rings.remove(name)
+    }
+
+    pub fn ring(&self, name: &str) -> Option<&ConsistentHash> {
+        self.rings.get(name)
+    }
+
+    pub fn ring_mut(&mut self, name: &str) -> Option<&mut ConsistentHash> {
+        self.rings.get_mut(name)
+    }
+
+    /// Names of the rings, sorted.
+    pub fn ring_names(&self) -> impl Iterator<Item = &String> {
+        self.rings.keys()
+    }
+
+    /// Adds `node` with `num_replicas` virtual nodes to every ring, or updates its replica count
+    /// where it is already present, and records it as the node's shared metadata.
+    pub fn add_node_to_all(&mut self, node: &Node, num_replicas: u32) {
+        for ring in self.rings.values_mut() {
+            ring.add_node(node, num_replicas);
+        }
+        self.nodes.insert(node.get_name().clone(), node.clone());
+    }
+
+    /// Removes a node from every ring holding it and forgets its metadata. Returns how many rings
+    /// it was removed from.
+    pub fn remove_node_from_all(&mut self, name: &str) -> usize {
+        self.nodes.remove(name);
+        self.rings.values_mut()
+            .filter_map(|ring| ring.try_remove_node(name).ok())
+            .count()
+    }
+
+    /// The node as last given to `add_node_to_all`. None if it was never added that way or has
+    /// been removed since.
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.nodes.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rings_share_topology() {
+        let mut manager = RingManager::new();
+        manager.insert_ring("sessions", ConsistentHash::new());
+        manager.insert_ring("blobs", ConsistentHash::new());
+        for i in 0..3 {
+            manager.add_node_to_all(&Node::new(format!("test_node_{}", i)), 4);
+        }
+        assert_eq!(manager.ring_names().collect::<Vec<_>>(), vec!["blobs", "sessions"]);
+        assert_eq!(manager.ring("blobs").unwrap().size(), 12);
+        assert_eq!(manager.ring("sessions").unwrap().size(), 12);
+
+        // rings can still diverge when changed one at a time
+        manager.ring_mut("blobs").unwrap().set_replicas("test_node_0", 8);
+        assert_eq!(manager.ring("blobs").unwrap().virtual_count("test_node_0"), 8);
+        assert_eq!(manager.ring("sessions").unwrap().virtual_count("test_node_0"), 4);
+
+        manager.insert_ring("late", ConsistentHash::new());
+        assert_eq!(manager.remove_node_from_all("test_node_1"), 2);
+        assert_eq!(manager.ring("sessions").unwrap().size(), 8);
+        assert_eq!(manager.node("test_node_1"), None);
+        assert_eq!(manager.node("test_node_2"), Some(&Node::new(String::from("test_node_2"))));
+
+        assert!(manager.remove_ring("late").is_some());
+        assert!(manager.ring("late").is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use std::f64::consts::LN_2;
+use std::time::{Duration, Instant};
+
+use crate::{ConsistentHash, Node, NodeState};
+
+/// Routes requests over a ring while holding each node to a requests-per-second limit, e.g. for
+/// distributed rate limiting where every node owns the counters of the keys it is given.
+///
+/// Each node's recent rate is tracked as load that halves every `half_life`, the same decay
+/// `assign_key_with_decay` uses. A node taking `r` requests a second settles at a decayed load
+/// of `r * half_life / ln 2`, so the limit becomes a fixed cap on that load, scaled by the node's
+/// capacity weight. A request walks clockwise from its key like a bounded-load lookup and goes
+/// to the first node under its cap, so a busy node spills over to its neighbours. A longer
+/// half-life smooths bursts out more but reacts more slowly to a change in rate.
+pub struct RateLimiter {
+    ring: ConsistentHash,
+    max_rate: f64,
+}
+
+impl RateLimiter {
+    /// Limits every node of `ring` to `max_rate` requests a second, measured over `half_life`.
+    pub fn new(mut ring: ConsistentHash, max_rate: f64, half_life: Duration) -> RateLimiter {
+        ring.set_half_life(half_life);
+        RateLimiter { ring, max_rate }
+    }
+
+    /// The node that should serve a request for `key` at `now`, counting the request against
+    /// it, or None if every node is at its rate limit. Unhealthy nodes are skipped.
+    pub fn allow(&mut self, key: String, now: Instant) -> Option<Node> {
+        self.ring.decay_to(now);
+        let hash = self.ring.key_hash(&key);
+        let node = self.ring.clockwise(hash)
+            .map(|(_hash, node)| node)
+            .find(|node| {
+                let name = node.get_name();
+                self.ring.node_state(name) != Some(NodeState::Unhealthy)
+                    && self.ring.effective_load(name) + 1.0 <= self.max_load(name)
+            })?
+            .clone();
+        self.ring.record_decayed_assignment(node.get_name(), now);
+        Some(node)
+    }
+
+    /// Requests a second a node has been taking, as of the last `allow`. None if the node is
+    /// unknown.
+    pub fn rate(&self, name: &str) -> Option<f64> {
+        let half_life = self.ring.half_life?;
+        Some(self.ring.decayed_load(name)? * LN_2 / half_life.as_secs_f64())
+    }
+
+    pub fn ring(&self) -> &ConsistentHash {
+        &self.ring
+    }
+
+    /// The ring, to add or remove nodes. Nodes keep their recent rate across changes.
+    pub fn ring_mut(&mut self) -> &mut ConsistentHash {
+        &mut self.ring
+    }
+
+    // decayed load at which a node is taking requests at its limit
+    fn max_load(&self, name: &str) -> f64 {
+        let half_life = self.ring.half_life.map_or(0.0, |half_life| half_life.as_secs_f64());
+        self.max_rate * half_life / LN_2 * self.ring.capacity_weight(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(nodes: usize, max_rate: f64) -> RateLimiter {
+        let mut ring = ConsistentHash::new();
+        for i in 0..nodes {
+            ring.add_node(&Node::new(format!("test_node_{}", i)), 10);
+        }
+        RateLimiter::new(ring, max_rate, Duration::from_secs(1))
+    }
+
+    #[test]
+    fn bursts_spill_over_and_then_stop() {
+        let mut limiter = limiter(2, 10.0);
+        let start = Instant::now();
+        let owner = limiter.ring().get_node(String::from("test_key1")).unwrap();
+        // 10 / ln 2 is about 14.4 requests of decayed load per node
+        let allowed: Vec<Node> = (0..40).filter_map(|_| limiter.allow(String::from("test_key1"), start)).collect();
+        assert_eq!(allowed.len(), 28);
+        assert!(allowed[..14].iter().all(|node| *node == owner));
+        assert!(allowed[14..].iter().all(|node| *node != owner));
+        assert!((limiter.rate(owner.get_name()).unwrap() - 14.0 * LN_2).abs() < 1e-9);
+
+        // after ten half-lives almost nothing is left of the burst, just the new request
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.allow(String::from("test_key1"), later), Some(owner.clone()));
+        assert!((limiter.rate(owner.get_name()).unwrap() - LN_2).abs() < 0.01);
+        assert_eq!(limiter.rate("test_node_9"), None);
+    }
+
+    #[test]
+    fn steady_rate_under_the_limit_is_allowed() {
+        let mut limiter = limiter(1, 10.0);
+        let start = Instant::now();
+        // 8 requests a second for a minute
+        for i in 0..480 {
+            let now = start + Duration::from_millis(125 * i);
+            assert!(limiter.allow(format!("test_key{}", i), now).is_some(), "rejected request {}", i);
+        }
+        let rate = limiter.rate("test_node_0").unwrap();
+        assert!(rate > 7.0 && rate < 9.0, "measured {} requests a second", rate);
+
+        limiter.ring_mut().set_node_state("test_node_0", NodeState::Unhealthy);
+        assert_eq!(limiter.allow(String::from("test_key1"), start + Duration::from_secs(60)), None);
+    }
+}
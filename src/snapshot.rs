@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+
+use crate::{ConsistentHash, Node, RingTxn};
+
+/// A ring shared between threads for the "topology changes rarely, lookups happen constantly"
+/// case.
+///
+/// Writers build a new ring and publish it with `store` or `update`; readers grab the current
+/// one with `load` or route straight through `get_node` without taking a lock. Each published
+/// ring is an immutable snapshot, so loads stay frozen at whatever the ring carried when it was
+/// published: this mode is for routing, not for `assign_key` style load tracking.
+///
+/// Publishing goes through `ArcSwap`, so a replaced ring is freed as soon as the last reader
+/// holding it from `load` drops it.
+pub struct SnapshotRing {
+    current: ArcSwap<ConsistentHash>,
+    // serialises writers, so concurrent updates apply one after the other
+    writer: Mutex<()>,
+}
+
+impl SnapshotRing {
+    pub fn new(ring: ConsistentHash) -> SnapshotRing {
+        SnapshotRing {
+            current: ArcSwap::from_pointee(ring),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The currently published ring.
+    pub fn load(&self) -> Arc<ConsistentHash> {
+        self.current.load_full()
+    }
+
+    /// Routes a key on the currently published ring.
+    pub fn get_node(&self, key: String) -> Option<Node> {
+        self.current.load().get_node(key)
+    }
+
+    /// Publishes `ring`, replacing the current one.
+    pub fn store(&self, ring: ConsistentHash) {
+        let _writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.current.store(Arc::new(ring));
+    }
+
+    /// Applies `f` to a copy of the current ring and publishes the result. Concurrent updates are
+    /// applied one after the other, so none of them is lost.
+    pub fn update(&self, f: impl FnOnce(&mut ConsistentHash)) {
+        let _writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut ring = (**self.current.load()).clone();
+        f(&mut ring);
+        self.current.store(Arc::new(ring));
+    }
+
+    /// Publishes the current ring with a batch of topology changes applied, as by
+    /// `ConsistentHash::transaction`. Readers see either the old ring or the new one, and an
+    /// aborted transaction publishes nothing. Returns whether the changes were published.
+    pub fn transaction(&self, f: impl FnOnce(&mut RingTxn)) -> bool {
+        let _writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut ring = (**self.current.load()).clone();
+        if !ring.transaction(f) {
+            return false;
+        }
+        self.current.store(Arc::new(ring));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn ring(nodes: usize) -> ConsistentHash {
+        let mut ch = ConsistentHash::new();
+        for i in 0..nodes {
+            ch.add_node(&Node::new(format!("test_node_{}", i)), 3);
+        }
+        ch
+    }
+
+    #[test]
+    fn readers_see_published_rings() {
+        let shared = SnapshotRing::new(ring(2));
+        let before = shared.load();
+        assert_eq!(before.size(), 6);
+
+        shared.update(|ch| ch.add_node(&Node::new(String::from("test_node_9")), 3));
+        assert_eq!(shared.load().size(), 9);
+        // the replaced ring is released right away, but snapshots taken earlier are unaffected
+        assert_eq!(Arc::strong_count(&before), 1);
+        assert_eq!(before.size(), 6);
+
+        assert!(shared.transaction(|txn| {
+            txn.remove_node("test_node_0");
+            txn.remove_node("test_node_1");
+        }));
+        assert_eq!(shared.load().size(), 3);
+        assert!(!shared.transaction(|txn| {
+            txn.remove_node("test_node_9");
+            txn.abort();
+        }));
+        assert_eq!(shared.load().size(), 3);
+
+        shared.store(ConsistentHash::new());
+        assert_eq!(shared.get_node(String::from("test_key1")), None);
+    }
+
+    #[test]
+    fn concurrent_reads_and_updates() {
+        let shared = Arc::new(SnapshotRing::new(ring(3)));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for i in 0..2000 {
+                    let ch = shared.load();
+                    assert_eq!(ch.size() % 3, 0);
+                    assert!(ch.get_node(format!("test_key{}", i)).is_some());
+                }
+            })
+        }).collect();
+        let writers: Vec<_> = (0..2).map(|writer| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for i in 0..50 {
+                    shared.update(|ch| ch.add_node(&Node::new(format!("writer_{}_node_{}", writer, i)), 3));
+                }
+            })
+        }).collect();
+        for handle in readers.into_iter().chain(writers) {
+            handle.join().unwrap();
+        }
+        assert_eq!(shared.load().size(), (3 + 100) * 3);
+    }
+}